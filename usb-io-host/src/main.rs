@@ -15,11 +15,10 @@ fn main() {
                     "USB IO board with serial {} ready to use",
                     connection.device().serial_number
                 );
+                println!("{:?}", connection.capabilities());
                 let base_gpioc_addr: u32 = 1073874944;
                 let base_rcc_addr: u32 = 1073887232;
-                let mut reg_value = connection.read32(base_rcc_addr + 48);
-                reg_value |= 1 << 2;
-                connection.write32(base_rcc_addr + 48, reg_value);
+                connection.set_bits32(base_rcc_addr + 48, 1 << 2);
 
                 let moder_value = 1 << 26;
                 let mut data: u32 = 1 << 13;
@@ -1,6 +1,7 @@
 use std::env;
 use std::fs;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -8,4 +9,33 @@ fn main() {
     fs::copy("memory.x", out_dir.join("memory.x")).unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
     println!("cargo:rerun-if-changed=memory.x");
-}
\ No newline at end of file
+
+    // Build identifier reported to the host, given explicitly or taken from
+    // the abbreviated hash of the checked out commit
+    println!("cargo:rustc-env=USB_IO_BUILD_ID={}", build_id());
+    println!("cargo:rerun-if-env-changed=USB_IO_BUILD_ID");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
+
+fn build_id() -> u32 {
+    if let Ok(id) = env::var("USB_IO_BUILD_ID") {
+        let parsed = match id.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => id.parse(),
+        };
+        return parsed.expect("USB_IO_BUILD_ID is not a 32-bit number");
+    }
+
+    Command::new("git")
+        .args(["rev-parse", "--short=8", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            // Hashes are made longer when ambiguous, the first 8 digits fit
+            let hash = String::from_utf8_lossy(&output.stdout);
+            u32::from_str_radix(hash.trim().get(..8)?, 16).ok()
+        })
+        .unwrap_or(0)
+}
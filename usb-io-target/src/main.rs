@@ -3,7 +3,7 @@
 
 use panic_halt as _;
 
-#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true)]
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [EXTI0])]
 mod app {
     use stm32f4xx_hal::{
         otg_fs::{UsbBus, UsbBusType, USB},
@@ -14,7 +14,64 @@ mod app {
 
     use stm32_device_signature::device_id_hex;
     use usb_device::prelude::*;
-    use usb_io::class::UsbIoClass;
+    use usb_io::{
+        class::UsbIoClass,
+        policy::{Access, AccessPolicy, Region},
+    };
+
+    /// Access to RCC peripheral clock enable registers, words only
+    const ENABLE_REGISTER: Access = Access::READ.union(Access::WRITE).union(Access::WIDTH_32);
+
+    /// Memory accessible from the host: flash and peripherals can be read,
+    /// only RAM, peripheral clock enables, GPIO, EXTI and timers (except TIM2
+    /// used by the monotonic) can be written
+    const ACCESS_REGIONS: &[Region] = &[
+        // Flash
+        Region::new(0x0800_0000, 256 * 1024, Access::READ_ONLY),
+        // SRAM
+        Region::new(0x2000_0000, 64 * 1024, Access::READ_WRITE),
+        // APB1, APB2 and AHB1 peripherals
+        Region::new(0x4000_0000, 0x2_8000, Access::READ_ONLY),
+        // TIM3 - TIM5
+        Region::new(0x4000_0400, 0xc00, Access::READ_WRITE),
+        // TIM1
+        Region::new(0x4001_0000, 0x400, Access::READ_WRITE),
+        // SYSCFG and EXTI, needed to route GPIO lines to forwarded interrupts
+        Region::new(0x4001_3800, 0x800, Access::READ_WRITE),
+        // TIM9 - TIM11
+        Region::new(0x4001_4000, 0xc00, Access::READ_WRITE),
+        // GPIOA - GPIOH
+        Region::new(0x4002_0000, 0x2000, Access::READ_WRITE),
+        // RCC AHB1ENR, needed to clock GPIO, the rest of RCC stays read only
+        Region::new(0x4002_3830, 4, ENABLE_REGISTER),
+        // RCC APB1ENR and APB2ENR, needed to clock timers and SYSCFG
+        Region::new(0x4002_3840, 8, ENABLE_REGISTER),
+    ];
+
+    /// Firmware build identifier set by the build script
+    const BUILD_ID: u32 = match u32::from_str_radix(env!("USB_IO_BUILD_ID"), 10) {
+        Ok(build_id) => build_id,
+        Err(_) => panic!("malformed build identifier"),
+    };
+
+    /// Interval between checks of a pending `WaitFor` request
+    const WAIT_POLL_INTERVAL_US: u32 = 50;
+
+    type Instant = <MicrosecMono as rtic::Monotonic>::Instant;
+
+    /// Delay of a soft reboot requested by the host, lets the control transfer complete
+    const REBOOT_DELAY_MS: u32 = 10;
+
+    /// EXTI interrupts the host may forward with the EXTI lines they serve,
+    /// EXTI0 is taken by the task dispatcher
+    const FORWARDED_INTERRUPTS: [(pac::Interrupt, u32); 6] = [
+        (pac::Interrupt::EXTI1, 1 << 1),
+        (pac::Interrupt::EXTI2, 1 << 2),
+        (pac::Interrupt::EXTI3, 1 << 3),
+        (pac::Interrupt::EXTI4, 1 << 4),
+        (pac::Interrupt::EXTI9_5, 0x3e0),
+        (pac::Interrupt::EXTI15_10, 0xfc00),
+    ];
 
     #[shared]
     struct Shared {
@@ -63,7 +120,22 @@ mod app {
             USB_BUS.replace(UsbBus::new(usb, &mut EP_MEMORY));
         }
 
-        let usb_io = UsbIoClass::new(unsafe { USB_BUS.as_ref().unwrap() });
+        // RTIC unmasks bound interrupts, they stay masked until the host
+        // enables forwarding
+        let mut irqs = [0; FORWARDED_INTERRUPTS.len()];
+        for ((interrupt, _), irq) in FORWARDED_INTERRUPTS.iter().zip(irqs.iter_mut()) {
+            pac::NVIC::mask(*interrupt);
+            *irq = *interrupt as u8;
+        }
+
+        let usb_io = UsbIoClass::new(
+            unsafe { USB_BUS.as_ref().unwrap() },
+            AccessPolicy::new(ACCESS_REGIONS),
+        )
+        .core_clock(sysclk.raw())
+        .build_id(BUILD_ID)
+        .forward_interrupts(&irqs)
+        .allow_reboot();
         let usb_dev =
             usb_io.make_device(unsafe { USB_BUS.as_ref().unwrap() }, Some(device_id_hex()));
         (Shared { usb_dev, usb_io }, Local {}, init::Monotonics(mono))
@@ -76,8 +148,75 @@ mod app {
             mut usb_io,
         } = cx.shared;
 
-        (&mut usb_dev, &mut usb_io).lock(|usb_dev, usb_io| {
-            usb_dev.poll(&mut [usb_io]);
-        });
+        let (wait_pending, reboot_requested) =
+            (&mut usb_dev, &mut usb_io).lock(|usb_dev, usb_io| {
+                usb_dev.poll(&mut [usb_io]);
+                (usb_io.wait_pending(), usb_io.reboot_requested())
+            });
+
+        if wait_pending {
+            // Fails when polling is already scheduled
+            wait_for::spawn_after(WAIT_POLL_INTERVAL_US.micros(), monotonics::now()).ok();
+        }
+        if reboot_requested {
+            reboot::spawn_after(REBOOT_DELAY_MS.millis()).ok();
+        }
+    }
+
+    #[task]
+    fn reboot(_: reboot::Context) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    /// Poll pending `WaitFor` request outside of the USB interrupt, `polled`
+    /// is the time of the previous check. The task starts late by the time
+    /// spent in interrupts and locks, so the time passed is measured.
+    #[task(shared=[usb_io])]
+    fn wait_for(mut cx: wait_for::Context, polled: Instant) {
+        let now = monotonics::now();
+        let elapsed_us = (now - polled).to_micros();
+        if cx.shared.usb_io.lock(|usb_io| usb_io.poll_wait(elapsed_us)) {
+            wait_for::spawn_after(WAIT_POLL_INTERVAL_US.micros(), now).ok();
+        }
+    }
+
+    /// Clear pending EXTI lines of forwarded interrupt `index` and report it to the host
+    fn forward(usb_io: &mut UsbIoClass<'static, UsbBusType>, index: usize) {
+        let (interrupt, lines) = FORWARDED_INTERRUPTS[index];
+        let exti = unsafe { &*pac::EXTI::ptr() };
+        let pending = exti.pr.read().bits() & lines;
+        exti.pr.write(|w| unsafe { w.bits(pending) });
+
+        usb_io.interrupt(interrupt as u8, monotonics::now().ticks());
+    }
+
+    #[task(binds=EXTI1, shared=[usb_io])]
+    fn exti1(mut cx: exti1::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 0));
+    }
+
+    #[task(binds=EXTI2, shared=[usb_io])]
+    fn exti2(mut cx: exti2::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 1));
+    }
+
+    #[task(binds=EXTI3, shared=[usb_io])]
+    fn exti3(mut cx: exti3::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 2));
+    }
+
+    #[task(binds=EXTI4, shared=[usb_io])]
+    fn exti4(mut cx: exti4::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 3));
+    }
+
+    #[task(binds=EXTI9_5, shared=[usb_io])]
+    fn exti9_5(mut cx: exti9_5::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 4));
+    }
+
+    #[task(binds=EXTI15_10, shared=[usb_io])]
+    fn exti15_10(mut cx: exti15_10::Context) {
+        cx.shared.usb_io.lock(|usb_io| forward(usb_io, 5));
     }
 }
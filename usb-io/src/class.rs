@@ -1,105 +1,720 @@
 use core::marker::PhantomData;
-use postcard::{from_bytes, to_slice};
 use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::descriptor::capability_type;
 use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
 use usb_device::Result;
 
 use crate::{
-    message::{Data, DataSize, Message},
-    usb::{MANUFACTURER, MESSAGE_MAX_SIZE, PID, PRODUCT, SERIAL_NUMBER, VID},
+    config::UsbIoConfig,
+    handler::{Command, RawMemory, TargetHandler},
+    message::{Capabilities, Event, Interrupt},
+    msos,
+    policy::AccessPolicy,
+    target::{Received, Target},
+    usb::{DEFAULT_PACKET_SIZE, PACKET_MAX_SIZE, REQUEST_REBOOT, REQUEST_RESET_PIPE},
 };
 
-pub struct UsbIoClass<'a, B: UsbBus> {
+/// USB-IO class with bulk endpoints of `PACKET_SIZE` bytes, messages which
+/// do not fit into a packet are split into several ones. Events pushed by the
+/// firmware are sent over a separate interrupt endpoint.
+pub struct UsbIoClass<
+    'a,
+    B: UsbBus,
+    const PACKET_SIZE: usize = DEFAULT_PACKET_SIZE,
+    H: TargetHandler = RawMemory,
+> {
+    config: UsbIoConfig,
     interface: InterfaceNumber,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
+    event_ep: EndpointIn<'a, B>,
+    /// A packet of an event is written to the event endpoint
+    event_busy: bool,
+    /// Host may request a soft reboot
+    reboot_allowed: bool,
+    /// Host requested a soft reboot
+    reboot_requested: bool,
+    target: Target<H>,
     _marker: PhantomData<B>,
 }
 
-impl<B: UsbBus> UsbIoClass<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>) -> UsbIoClass<B> {
+impl<B: UsbBus, const PACKET_SIZE: usize> UsbIoClass<'_, B, PACKET_SIZE> {
+    /// Create class, host accesses are checked against `policy`
+    pub fn new(alloc: &UsbBusAllocator<B>, policy: AccessPolicy) -> UsbIoClass<'_, B, PACKET_SIZE> {
+        UsbIoClass::with_handler(alloc, policy, RawMemory::default())
+    }
+
+    /// Set core clock frequency in Hz, used to time delays of batches
+    pub fn core_clock(mut self, hz: u32) -> Self {
+        self.target.handler.core_clock = hz;
+        self
+    }
+}
+
+impl<B: UsbBus, const PACKET_SIZE: usize, H: TargetHandler> UsbIoClass<'_, B, PACKET_SIZE, H> {
+    /// Create class dispatching memory accesses of host requests to `handler`
+    pub fn with_handler(
+        alloc: &UsbBusAllocator<B>,
+        policy: AccessPolicy,
+        handler: H,
+    ) -> UsbIoClass<'_, B, PACKET_SIZE, H> {
+        // Full speed bulk endpoints are 8, 16, 32 or 64 bytes
+        const {
+            assert!(
+                PACKET_SIZE.is_power_of_two() && PACKET_SIZE >= 8 && PACKET_SIZE <= PACKET_MAX_SIZE
+            )
+        };
+
         UsbIoClass {
+            config: UsbIoConfig::new(),
             interface: alloc.interface(),
-            read_ep: alloc.bulk(MESSAGE_MAX_SIZE),
-            write_ep: alloc.bulk(MESSAGE_MAX_SIZE),
+            read_ep: alloc.bulk(PACKET_SIZE as u16),
+            write_ep: alloc.bulk(PACKET_SIZE as u16),
+            event_ep: alloc.interrupt(PACKET_SIZE as u16, 1),
+            event_busy: false,
+            reboot_allowed: false,
+            reboot_requested: false,
+            target: Target::new(handler, policy, PACKET_SIZE as u16),
             _marker: PhantomData,
         }
     }
 
-    pub fn make_device<'a, 'b>(
-        &'a self,
+    /// Set identity of the board, used by the interface descriptor and `make_device`
+    pub fn config(mut self, config: UsbIoConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set firmware build identifier reported to the host
+    pub fn build_id(mut self, build_id: u32) -> Self {
+        self.target.build_id = build_id;
+        self
+    }
+
+    /// Register custom command `id`, answering `Message::Custom` requests
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than `COMMANDS_MAX` commands are registered.
+    pub fn command(mut self, id: u16, command: Command<H>) -> Self {
+        self.target.register(id, command);
+        self
+    }
+
+    /// Allow host to forward interrupt lines `irqs`, the firmware binds
+    /// handlers to them which call `interrupt`
+    pub fn forward_interrupts(mut self, irqs: &[u8]) -> Self {
+        for &irq in irqs {
+            self.target.interrupts.allow(irq);
+        }
+        self
+    }
+
+    /// Accept soft reboot requests of the host, the firmware polls
+    /// `reboot_requested` and resets the core
+    pub fn allow_reboot(mut self) -> Self {
+        self.reboot_allowed = true;
+        self
+    }
+
+    /// Host requested a soft reboot, the firmware should reset the core
+    /// shortly to let the control transfer complete
+    pub fn reboot_requested(&self) -> bool {
+        self.reboot_requested
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.target.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.target.handler
+    }
+
+    /// Capabilities reported in response to `Message::Hello`
+    pub fn capabilities(&self) -> Capabilities {
+        self.target.capabilities()
+    }
+
+    /// Build device with the identity of the configuration, `serial`
+    /// overrides its serial number
+    pub fn make_device<'b>(
+        &self,
         usb_bus: &'b UsbBusAllocator<B>,
         serial: Option<&'static str>,
     ) -> UsbDevice<'b, B> {
-        let serial = serial.unwrap_or(SERIAL_NUMBER);
-        UsbDeviceBuilder::new(&usb_bus, UsbVidPid(VID, PID))
-            .manufacturer(MANUFACTURER)
-            .product(PRODUCT)
-            .serial_number(serial)
+        let config = &self.config;
+        UsbDeviceBuilder::new(usb_bus, UsbVidPid(config.vid, config.pid))
+            .manufacturer(config.manufacturer)
+            .product(config.product)
+            .serial_number(serial.unwrap_or(config.serial_number))
+            .device_release(config.device_release)
+            .self_powered(config.self_powered)
+            .max_power(config.max_power as usize)
             .build()
     }
+
+    /// A `WaitFor` request is waiting, `poll_wait` has to be called until it completes
+    pub fn wait_pending(&self) -> bool {
+        self.target.wait_pending()
+    }
+
+    /// Check condition of the pending `WaitFor` request, `elapsed_us` passed
+    /// since the previous check. Returns `true` while the request is pending.
+    ///
+    /// Checks are deferred out of the USB interrupt, so the firmware has to
+    /// call this periodically while `wait_pending` is true.
+    pub fn poll_wait(&mut self, elapsed_us: u32) -> bool {
+        if self.target.poll_wait(elapsed_us) {
+            self.respond();
+        }
+        self.target.wait_pending()
+    }
+
+    /// Queue event for the host, returns `false` if the queue is full.
+    ///
+    /// Dropped events are reported to the host with `Event::Lost` once the
+    /// queue is drained.
+    pub fn push_event(&mut self, event: Event) -> bool {
+        let queued = self.target.events.push(event);
+        if !self.event_busy {
+            self.write_event_packet();
+        }
+        queued
+    }
+
+    /// Report occurrence of interrupt line `irq` at `timestamp_us` if the
+    /// host enabled its forwarding, returns `false` if it was not reported
+    pub fn interrupt(&mut self, irq: u8, timestamp_us: u32) -> bool {
+        self.target.interrupts.is_enabled(irq)
+            && self.push_event(Event::Interrupt(Interrupt { irq, timestamp_us }))
+    }
+
+    /// Send next packet of the queued events
+    fn write_event_packet(&mut self) {
+        let mut buf = [0; PACKET_SIZE];
+        self.event_busy = match self.target.events.next_packet(&mut buf) {
+            Some(size) if self.event_ep.write(&buf[..size]).is_ok() => true,
+            Some(_) => {
+                // Drop the event, the host can't reassemble it anyway
+                self.target.events.clear_current();
+                false
+            }
+            None => false,
+        };
+    }
+
+    /// Start sending loaded response, the read endpoint stays stalled until it is sent
+    fn respond(&mut self) {
+        if !self.write_packet() {
+            self.release();
+        }
+    }
+
+    /// Hold off the next request until the whole response is sent
+    fn hold(&mut self) {
+        self.read_ep.stall();
+        self.target.holding = true;
+    }
+
+    fn release(&mut self) {
+        self.read_ep.unstall();
+        self.target.holding = false;
+    }
+
+    /// Vendor request addressed to the USB-IO interface
+    fn is_vendor_request(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+    }
+
+    /// Send next packet of the pending response
+    fn write_packet(&mut self) -> bool {
+        let mut buf = [0; PACKET_SIZE];
+        match self.target.next_packet(&mut buf) {
+            Some(size) if self.write_ep.write(&buf[..size]).is_ok() => true,
+            // Drop the rest of the response, the host will time out and retry
+            _ => {
+                self.target.clear_response();
+                false
+            }
+        }
+    }
 }
 
-impl<B: UsbBus> UsbClass<B> for UsbIoClass<'_, B> {
+impl<B: UsbBus, const PACKET_SIZE: usize, H: TargetHandler> UsbClass<B>
+    for UsbIoClass<'_, B, PACKET_SIZE, H>
+{
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface(self.interface, 0xff, 0, 0)?;
+        writer.interface(
+            self.interface,
+            self.config.interface_class,
+            self.config.interface_sub_class,
+            self.config.interface_protocol,
+        )?;
         writer.endpoint(&self.write_ep)?;
         writer.endpoint(&self.read_ep)?;
+        writer.endpoint(&self.event_ep)?;
         Ok(())
     }
 
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        if self.config.interface_guid.is_some() {
+            writer.capability(capability_type::PLATFORM, &msos::platform_capability())?;
+        }
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if self.is_vendor_request(&req) {
+            let mut buf = [0; 16];
+            match self.target.control_in(req.request, &mut buf) {
+                Some(size) => xfer.accept_with(&buf[..size]).ok(),
+                None => xfer.reject().ok(),
+            };
+            return;
+        }
+
+        let guid = match self.config.interface_guid {
+            Some(guid) => guid,
+            None => return,
+        };
+        if req.request_type != RequestType::Vendor
+            || req.recipient != Recipient::Device
+            || req.request != msos::VENDOR_CODE
+        {
+            return;
+        }
+
+        if req.index == msos::DESCRIPTOR_INDEX {
+            xfer.accept(|buf| {
+                msos::write_descriptor_set(buf, guid).ok_or(UsbError::BufferOverflow)
+            })
+            .ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_vendor_request(&req) {
+            return;
+        }
+
+        let accepted = match req.request {
+            REQUEST_REBOOT => {
+                self.reboot_requested = self.reboot_allowed;
+                self.reboot_allowed
+            }
+            request => self.target.control_out(request),
+        };
+        if !accepted {
+            xfer.reject().ok();
+            return;
+        }
+
+        if req.request == REQUEST_RESET_PIPE {
+            // The completion of the last response packet may have been lost
+            self.release();
+        }
+        xfer.accept().ok();
+    }
+
+    fn reset(&mut self) {
+        self.target.reset();
+        self.event_busy = false;
+    }
+
     fn endpoint_out(&mut self, addr: EndpointAddress) {
         if addr == self.read_ep.address() {
-            let mut buf = [0; MESSAGE_MAX_SIZE as usize];
-            let size = self.read_ep.read(&mut buf).unwrap();
+            let mut buf = [0; PACKET_SIZE];
+            let size = match self.read_ep.read(&mut buf) {
+                Ok(size) => size,
+                Err(_) => return,
+            };
+
+            match self.target.receive(&buf[..size]) {
+                Received::Incomplete => (),
+                Received::Response => {
+                    // Hold off the next request until the whole response is sent
+                    self.hold();
+                    self.respond();
+                }
+                Received::Waiting => {
+                    // Check right away, `poll_wait` answers once done
+                    self.hold();
+                    self.poll_wait(0);
+                }
+            }
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() && !self.write_packet() {
+            self.release();
+        } else if addr == self.event_ep.address() {
+            self.write_event_packet();
+        }
+    }
+}
+
+// The mock bus records traffic in std collections
+#[cfg(all(test, feature = "std"))]
+mod mock;
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::mock::{BufferMemory, MockBus};
+    use super::*;
+    use crate::{
+        frame::{Assembler, Fragmenter, FRAME_END, FRAME_START},
+        message::{Data, DataSize, Envelope, ErrorCode, Message, Status, PROTOCOL_VERSION},
+        policy::{Access, Region},
+        usb::{FRAME_MAX_SIZE, REQUEST_STATUS, REQUEST_VERSION},
+    };
+    use postcard::from_bytes;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const RAM: u32 = 0x2000_0000;
+    const REGIONS: &[Region] = &[Region::new(RAM, 0x80, Access::READ_WRITE)];
+
+    type TestClass<'a> = UsbIoClass<'a, MockBus, 8, BufferMemory>;
 
-            self.read_ep.stall();
+    fn new_class(alloc: &UsbBusAllocator<MockBus>) -> TestClass<'_> {
+        let memory = BufferMemory {
+            base: RAM,
+            bytes: vec![0; 0x100],
+        };
+        UsbIoClass::with_handler(alloc, AccessPolicy::new(REGIONS), memory)
+    }
+
+    fn poll(device: &mut UsbDevice<MockBus>, class: &mut TestClass) {
+        while device.poll(&mut [&mut *class]) {}
+    }
 
-            if size < 1 {
-                return;
+    /// Run control IN transfer of `setup`, returns the data stage
+    fn control_in(
+        bus: &MockBus,
+        device: &mut UsbDevice<MockBus>,
+        class: &mut TestClass,
+        setup: [u8; 8],
+    ) -> Vec<u8> {
+        bus.setup(setup);
+        let mut data = vec![];
+        loop {
+            poll(device, class);
+            match bus.host_read(0) {
+                Some(packet) => data.extend(packet),
+                None => return data,
             }
+        }
+    }
 
-            if let Ok(message) = from_bytes(&buf) {
-                let return_message = match message {
-                    Message::Ping => Message::Pong,
-                    Message::Set(address, data) => {
-                        unsafe {
-                            match data {
-                                Data::U8(b) => (address as *mut u8).write_volatile(b),
-                                Data::U16(b) => (address as *mut u16).write_volatile(b),
-                                Data::U32(b) => (address as *mut u32).write_volatile(b),
-                            }
-                        }
-                        Message::Ack
-                    }
-                    Message::Get(address, data_size) => unsafe {
-                        match data_size {
-                            DataSize::U8 => {
-                                Message::Data(Data::U8((address as *const u8).read_volatile()))
-                            }
-                            DataSize::U16 => {
-                                Message::Data(Data::U16((address as *const u16).read_volatile()))
-                            }
-                            DataSize::U32 => {
-                                Message::Data(Data::U32((address as *const u32).read_volatile()))
-                            }
-                        }
-                    },
-                    _ => Message::Nop,
-                };
-
-                let slice = to_slice(&return_message, &mut buf).unwrap();
-
-                self.write_ep.write(&slice).unwrap();
-            } else {
-                return;
+    fn request(
+        bus: &MockBus,
+        device: &mut UsbDevice<MockBus>,
+        class: &mut TestClass,
+        tag: u16,
+        message: Message,
+    ) -> Envelope {
+        send_request(bus, device, class, tag, message);
+        read_response(bus, device, class)
+    }
+
+    fn send_request(
+        bus: &MockBus,
+        device: &mut UsbDevice<MockBus>,
+        class: &mut TestClass,
+        tag: u16,
+        message: Message,
+    ) {
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(&Envelope::new(tag, message)).unwrap();
+        let mut buf = [0; 8];
+        while let Some(size) = fragmenter.next_packet(&mut buf) {
+            bus.host_write(1, &buf[..size]).unwrap();
+            poll(device, class);
+        }
+    }
+
+    fn read_response(
+        bus: &MockBus,
+        device: &mut UsbDevice<MockBus>,
+        class: &mut TestClass,
+    ) -> Envelope {
+        let mut assembler = Assembler::<FRAME_MAX_SIZE>::new();
+        loop {
+            let packet = bus.host_read(1).unwrap();
+            // Next request is held off until the whole response is read
+            assert!(bus.host_write(1, &[FRAME_START | FRAME_END]).is_err());
+            poll(device, class);
+            if let Some(frame) = assembler.push(&packet).unwrap() {
+                return from_bytes(frame).unwrap();
             }
         }
     }
 
-    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
-        if addr == self.write_ep.address() {
-            self.read_ep.unstall();
+    #[test]
+    fn test_configuration_descriptor() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        // GET_DESCRIPTOR(CONFIGURATION) of 255 bytes
+        let setup = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00];
+        let descriptor = control_in(&bus, &mut device, &mut class, setup);
+
+        let interface = [0x09, 0x04, 0x00, 0x00, 0x03, 0xff, 0x00, 0x00, 0x00];
+        let endpoint_in = [0x07, 0x05, 0x81, 0x02, 0x08, 0x00, 0x00];
+        let endpoint_out = [0x07, 0x05, 0x01, 0x02, 0x08, 0x00, 0x00];
+        let endpoint_event = [0x07, 0x05, 0x82, 0x03, 0x08, 0x00, 0x01];
+        assert_eq!(
+            [&interface[..], &endpoint_in, &endpoint_out, &endpoint_event].concat(),
+            descriptor[9..]
+        );
+    }
+
+    #[test]
+    fn test_device_identity() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let config = UsbIoConfig::new()
+            .vid_pid(0x1209, 0x0001)
+            .device_release(0x0120)
+            .self_powered(true)
+            .max_power(200)
+            .interface(0xff, 0x01, 0x02);
+        let mut class = new_class(&alloc).config(config);
+        let mut device = class.make_device(&alloc, None);
+
+        // GET_DESCRIPTOR(DEVICE)
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let descriptor = control_in(&bus, &mut device, &mut class, setup);
+        assert_eq!([0x09, 0x12, 0x01, 0x00, 0x20, 0x01], descriptor[8..14]);
+
+        // GET_DESCRIPTOR(CONFIGURATION)
+        let setup = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00];
+        let descriptor = control_in(&bus, &mut device, &mut class, setup);
+        // Self powered, 200 mA in 2 mA units
+        assert_eq!([0xc0, 100], descriptor[7..9]);
+        assert_eq!([0xff, 0x01, 0x02], descriptor[14..17]);
+    }
+
+    #[test]
+    fn test_ms_os_descriptors() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        // GET_DESCRIPTOR(BOS)
+        let setup = [0x80, 0x06, 0x00, 0x0f, 0x00, 0x00, 0xff, 0x00];
+        let bos = control_in(&bus, &mut device, &mut class, setup);
+        assert_eq!([0x05, 0x0f, 0x28, 0x00, 0x02], bos[..5]);
+        // USB 2.0 extension follows the header, then the platform capability
+        let mut platform = vec![0x1c, 0x10, 0x05];
+        platform.extend(msos::platform_capability());
+        assert_eq!(platform, bos[12..]);
+
+        // Vendor request for the descriptor set
+        let setup = [0xc0, msos::VENDOR_CODE, 0x00, 0x00, 0x07, 0x00, 0xff, 0x00];
+        let set = control_in(&bus, &mut device, &mut class, setup);
+        let mut expected = [0; msos::SET_LEN];
+        msos::write_descriptor_set(&mut expected, crate::usb::INTERFACE_GUID).unwrap();
+        assert_eq!(expected[..], set[..]);
+
+        // Disabled descriptors are not advertised
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let config = UsbIoConfig::new().interface_guid(None);
+        let mut class = new_class(&alloc).config(config);
+        let mut device = class.make_device(&alloc, None);
+        let setup = [0x80, 0x06, 0x00, 0x0f, 0x00, 0x00, 0xff, 0x00];
+        let bos = control_in(&bus, &mut device, &mut class, setup);
+        assert_eq!([0x05, 0x0f, 0x0c, 0x00, 0x01], bos[..5]);
+    }
+
+    #[test]
+    fn test_vendor_requests() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        let status = |bus: &MockBus, device: &mut _, class: &mut _| -> Status {
+            let setup = [0xc1, REQUEST_STATUS, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00];
+            from_bytes(&control_in(bus, device, class, setup)).unwrap()
+        };
+        let control_out = |bus: &MockBus, device: &mut _, class: &mut _, request| -> bool {
+            bus.setup([0x41, request, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            poll(device, class);
+            // Status stage of accepted requests
+            bus.host_read(0).is_some()
+        };
+
+        let setup = [0xc1, REQUEST_VERSION, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let version = control_in(&bus, &mut device, &mut class, setup);
+        assert_eq!(PROTOCOL_VERSION.to_le_bytes()[..], version[..]);
+
+        // Response is sent, but its completion is lost
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(&Envelope::new(1, Message::Ping)).unwrap();
+        let mut buf = [0; 8];
+        let size = fragmenter.next_packet(&mut buf).unwrap();
+        bus.host_write(1, &buf[..size]).unwrap();
+        poll(&mut device, &mut class);
+        assert!(bus.host_write(1, &buf[..size]).is_err());
+        let expected = Status {
+            responding: true,
+            ..Status::default()
+        };
+        assert_eq!(expected, status(&bus, &mut device, &mut class));
+
+        assert!(control_out(
+            &bus,
+            &mut device,
+            &mut class,
+            REQUEST_RESET_PIPE
+        ));
+        // Host drains the lingering packet
+        assert!(bus.host_read(1).is_some());
+        poll(&mut device, &mut class);
+        assert_eq!(Status::default(), status(&bus, &mut device, &mut class));
+        let response = request(&bus, &mut device, &mut class, 2, Message::Ping);
+        assert_eq!(Envelope::new(2, Message::Pong), response);
+    }
+
+    #[test]
+    fn test_wait_for_timeout() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        let timeout = Duration::from_millis(20);
+        let wait = Message::WaitFor {
+            address: RAM,
+            mask: 1,
+            expected: 1,
+            timeout_us: timeout.as_micros() as u32,
+        };
+        send_request(&bus, &mut device, &mut class, 1, wait);
+        assert!(class.wait_pending());
+
+        // Polls run later than scheduled, the time passed is measured
+        let started = Instant::now();
+        let mut polled = started;
+        while class.wait_pending() {
+            thread::sleep(Duration::from_millis(3));
+            let elapsed_us = polled.elapsed().as_micros() as u32;
+            polled = Instant::now();
+            class.poll_wait(elapsed_us);
+        }
+        let waited = started.elapsed();
+        assert!(waited >= timeout);
+        // Late by one poll at most, well within the margin the host allows
+        assert!(waited < timeout + Duration::from_millis(15));
+
+        let response = read_response(&bus, &mut device, &mut class);
+        assert_eq!(
+            Envelope::new(1, Message::Error(ErrorCode::Timeout)),
+            response
+        );
+    }
+
+    #[test]
+    fn test_reboot() {
+        let control_out = |bus: &MockBus, device: &mut _, class: &mut _| -> bool {
+            bus.setup([0x41, REQUEST_REBOOT, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            poll(device, class);
+            // Status stage of accepted requests
+            bus.host_read(0).is_some()
+        };
+
+        // Reboot is rejected unless the firmware allows it
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+        assert!(!control_out(&bus, &mut device, &mut class));
+        assert!(!class.reboot_requested());
+
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc).allow_reboot();
+        let mut device = class.make_device(&alloc, None);
+        assert!(control_out(&bus, &mut device, &mut class));
+        assert!(class.reboot_requested());
+    }
+
+    #[test]
+    fn test_requests() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        let response = request(&bus, &mut device, &mut class, 1, Message::Hello);
+        assert_eq!(
+            Envelope::new(1, Message::Capabilities(class.capabilities())),
+            response
+        );
+
+        let set = Message::Set(RAM + 4, Data::U32(0x1234_5678));
+        let response = request(&bus, &mut device, &mut class, 2, set);
+        assert_eq!(Envelope::new(2, Message::Ack), response);
+        assert_eq!([0x78, 0x56, 0x34, 0x12], class.handler().bytes[4..8]);
+
+        let get = Message::Get(RAM + 6, DataSize::U16);
+        let response = request(&bus, &mut device, &mut class, 3, get);
+        assert_eq!(Envelope::new(3, Message::Data(Data::U16(0x1234))), response);
+
+        for (message, code) in [
+            (Message::Get(RAM + 0x80, DataSize::U8), ErrorCode::Forbidden),
+            (Message::Get(RAM + 1, DataSize::U32), ErrorCode::Unaligned),
+        ] {
+            let response = request(&bus, &mut device, &mut class, 4, message);
+            assert_eq!(Envelope::new(4, Message::Error(code)), response);
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let bus = MockBus::default();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut class = new_class(&alloc);
+        let mut device = class.make_device(&alloc, None);
+
+        let events = [
+            Event::Gpio {
+                line: 3,
+                rising: true,
+            },
+            Event::Changed {
+                address: RAM,
+                value: 0xdead_beef,
+            },
+        ];
+        for event in events.iter().cloned() {
+            assert!(class.push_event(event));
+        }
+
+        let mut assembler = Assembler::<FRAME_MAX_SIZE>::new();
+        let mut received = vec![];
+        while let Some(packet) = bus.host_read(2) {
+            poll(&mut device, &mut class);
+            if let Some(frame) = assembler.push(&packet).unwrap() {
+                received.push(from_bytes::<Event>(frame).unwrap());
+            }
         }
+        assert_eq!(events[..], received[..]);
     }
 }
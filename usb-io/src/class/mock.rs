@@ -0,0 +1,209 @@
+//! `UsbBus` recording endpoint traffic, the test plays the role of the host.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use usb_device::{
+    bus::{PollResult, UsbBus},
+    endpoint::{EndpointAddress, EndpointType},
+    UsbDirection, UsbError,
+};
+
+use crate::{
+    handler::TargetHandler,
+    message::{Data, DataSize, ErrorCode},
+};
+
+/// Packet received by an OUT endpoint
+struct OutPacket {
+    setup: bool,
+    bytes: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    next_index: [u8; 2],
+    max_packet_size: BTreeMap<u8, u16>,
+    out: BTreeMap<usize, VecDeque<OutPacket>>,
+    /// Packets written by the device, not read by the host yet
+    in_flight: BTreeMap<usize, Vec<u8>>,
+    in_complete: u16,
+    stalled: BTreeSet<u8>,
+}
+
+/// Clones share the state, one is moved into the allocator and one kept by the test
+#[derive(Clone, Default)]
+pub struct MockBus {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBus {
+    /// Host sends SETUP packet to control endpoint
+    pub fn setup(&self, packet: [u8; 8]) {
+        self.push_out(0, true, &packet);
+    }
+
+    /// Host sends `packet` to OUT endpoint `index`, fails if the endpoint is stalled
+    pub fn host_write(&self, index: usize, packet: &[u8]) -> Result<(), UsbError> {
+        let address = EndpointAddress::from_parts(index, UsbDirection::Out);
+        if self.is_stalled(address) {
+            return Err(UsbError::InvalidState);
+        }
+        self.push_out(index, false, packet);
+        Ok(())
+    }
+
+    /// Host receives packet written to IN endpoint `index`
+    pub fn host_read(&self, index: usize) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let packet = state.in_flight.remove(&index)?;
+        state.in_complete |= 1 << index;
+        Some(packet)
+    }
+
+    fn push_out(&self, index: usize, setup: bool, bytes: &[u8]) {
+        let packet = OutPacket {
+            setup,
+            bytes: bytes.to_vec(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.out.entry(index).or_default().push_back(packet);
+    }
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        let mut state = self.state.lock().unwrap();
+        let address = match (ep_addr, ep_type) {
+            (Some(address), _) => address,
+            (None, EndpointType::Control) => EndpointAddress::from_parts(0, ep_dir),
+            (None, _) => {
+                let next = &mut state.next_index[ep_dir as usize >> 7];
+                *next += 1;
+                EndpointAddress::from_parts(*next as usize, ep_dir)
+            }
+        };
+        state
+            .max_packet_size
+            .insert(address.into(), max_packet_size);
+        Ok(address)
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight.contains_key(&ep_addr.index()) {
+            return Err(UsbError::WouldBlock);
+        }
+        if buf.len() > state.max_packet_size[&u8::from(ep_addr)] as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        state.in_flight.insert(ep_addr.index(), buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let packet = state
+            .out
+            .get_mut(&ep_addr.index())
+            .and_then(VecDeque::pop_front)
+            .ok_or(UsbError::WouldBlock)?;
+        if packet.bytes.len() > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..packet.bytes.len()].copy_from_slice(&packet.bytes);
+        Ok(packet.bytes.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        if stalled {
+            state.stalled.insert(ep_addr.into());
+        } else {
+            state.stalled.remove(&ep_addr.into());
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.state.lock().unwrap().stalled.contains(&ep_addr.into())
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state.lock().unwrap();
+        let (mut ep_out, mut ep_setup) = (0, 0);
+        for (index, packets) in state.out.iter() {
+            match packets.front() {
+                Some(packet) if packet.setup => ep_setup |= 1 << index,
+                Some(_) => ep_out |= 1 << index,
+                None => (),
+            }
+        }
+        let ep_in_complete = core::mem::take(&mut state.in_complete);
+
+        if ep_out | ep_in_complete | ep_setup == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+/// Memory backed by a test buffer mapped at `base`
+pub struct BufferMemory {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl BufferMemory {
+    fn range(&self, address: u32, len: u32) -> Result<std::ops::Range<usize>, ErrorCode> {
+        let offset = address.checked_sub(self.base).ok_or(ErrorCode::BusFault)? as usize;
+        if offset + len as usize > self.bytes.len() {
+            return Err(ErrorCode::BusFault);
+        }
+        Ok(offset..offset + len as usize)
+    }
+}
+
+impl TargetHandler for BufferMemory {
+    fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+        let bytes = &self.bytes[self.range(address, size.bytes())?];
+        Ok(match size {
+            DataSize::U8 => Data::U8(bytes[0]),
+            DataSize::U16 => Data::U16(u16::from_le_bytes(bytes.try_into().unwrap())),
+            DataSize::U32 => Data::U32(u32::from_le_bytes(bytes.try_into().unwrap())),
+        })
+    }
+
+    fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode> {
+        let range = self.range(address, data.size().bytes())?;
+        match data {
+            Data::U8(value) => self.bytes[range].copy_from_slice(&[value]),
+            Data::U16(value) => self.bytes[range].copy_from_slice(&value.to_le_bytes()),
+            Data::U32(value) => self.bytes[range].copy_from_slice(&value.to_le_bytes()),
+        }
+        Ok(())
+    }
+}
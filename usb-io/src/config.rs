@@ -0,0 +1,132 @@
+//! Identity of a USB-IO board variant, shared by the target class and host discovery.
+//!
+//! ```
+//! use usb_io::config::UsbIoConfig;
+//!
+//! const BOARD: UsbIoConfig = UsbIoConfig::new()
+//!     .vid_pid(0x16c0, 0x27de)
+//!     .product("USB-IO mini")
+//!     .device_release(0x0200)
+//!     .max_power(200);
+//! ```
+
+use crate::{
+    msos::GUID_LEN,
+    usb::{INTERFACE_GUID, MANUFACTURER, PID, PRODUCT, SERIAL_NUMBER, VID},
+};
+
+/// Device and interface descriptor fields of a USB-IO board
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UsbIoConfig {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    /// Serial number used when the firmware does not provide one
+    pub serial_number: &'static str,
+    /// Device release number in BCD
+    pub device_release: u16,
+    pub self_powered: bool,
+    /// Maximum current drawn from the bus in mA, up to 500
+    pub max_power: u16,
+    pub interface_class: u8,
+    pub interface_sub_class: u8,
+    pub interface_protocol: u8,
+    /// Device interface GUID registered by WinUSB, `None` disables Microsoft OS descriptors
+    pub interface_guid: Option<&'static str>,
+}
+
+impl UsbIoConfig {
+    /// Identity of the reference board, a vendor specific interface
+    pub const fn new() -> Self {
+        Self {
+            vid: VID,
+            pid: PID,
+            manufacturer: MANUFACTURER,
+            product: PRODUCT,
+            serial_number: SERIAL_NUMBER,
+            device_release: 0x0010,
+            self_powered: false,
+            max_power: 100,
+            interface_class: 0xff,
+            interface_sub_class: 0,
+            interface_protocol: 0,
+            interface_guid: Some(INTERFACE_GUID),
+        }
+    }
+
+    pub const fn vid_pid(mut self, vid: u16, pid: u16) -> Self {
+        self.vid = vid;
+        self.pid = pid;
+        self
+    }
+
+    pub const fn manufacturer(mut self, manufacturer: &'static str) -> Self {
+        self.manufacturer = manufacturer;
+        self
+    }
+
+    pub const fn product(mut self, product: &'static str) -> Self {
+        self.product = product;
+        self
+    }
+
+    pub const fn serial_number(mut self, serial_number: &'static str) -> Self {
+        self.serial_number = serial_number;
+        self
+    }
+
+    /// Set device release number in BCD, e.g. `0x0120` for 1.2
+    pub const fn device_release(mut self, device_release: u16) -> Self {
+        self.device_release = device_release;
+        self
+    }
+
+    pub const fn self_powered(mut self, self_powered: bool) -> Self {
+        self.self_powered = self_powered;
+        self
+    }
+
+    /// Set maximum current drawn from the bus in mA
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_power` is more than 500 mA.
+    pub const fn max_power(mut self, max_power: u16) -> Self {
+        assert!(max_power <= 500, "max_power is too much");
+        self.max_power = max_power;
+        self
+    }
+
+    pub const fn interface(mut self, class: u8, sub_class: u8, protocol: u8) -> Self {
+        self.interface_class = class;
+        self.interface_sub_class = sub_class;
+        self.interface_protocol = protocol;
+        self
+    }
+
+    /// Set device interface GUID in registry format,
+    /// `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `guid` is not 38 characters long.
+    pub const fn interface_guid(mut self, guid: Option<&'static str>) -> Self {
+        if let Some(guid) = guid {
+            assert!(guid.len() == GUID_LEN, "malformed interface GUID");
+        }
+        self.interface_guid = guid;
+        self
+    }
+
+    /// Device has the vendor and product ids of this configuration
+    pub fn matches(&self, vid: u16, pid: u16) -> bool {
+        self.vid == vid && self.pid == pid
+    }
+}
+
+impl Default for UsbIoConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,202 @@
+//! Splitting of serialized messages into bulk packets and their reassembly.
+//!
+//! Every packet starts with a one byte header carrying [`FRAME_START`] and
+//! [`FRAME_END`] flags, followed by a chunk of the serialized message. A
+//! message which fits into a single packet has both flags set.
+
+use postcard::to_slice;
+use serde::Serialize;
+
+/// First packet of a frame
+pub const FRAME_START: u8 = 0x01;
+/// Last packet of a frame
+pub const FRAME_END: u8 = 0x02;
+
+/// Size of the packet header
+pub const FRAME_HEADER_SIZE: usize = 1;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum FrameError {
+    /// Frame does not fit into the assembly buffer
+    Overflow,
+    /// Packet does not belong to any started frame
+    Unexpected,
+}
+
+/// Collects packets until a whole frame is received
+pub struct Assembler<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    active: bool,
+    overflow: bool,
+}
+
+impl<const N: usize> Assembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            active: false,
+            overflow: false,
+        }
+    }
+
+    /// Drop partially received frame
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.active = false;
+        self.overflow = false;
+    }
+
+    /// A frame is partially received
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Add received packet, returns whole frame when the last packet arrives.
+    ///
+    /// Errors are reported only with the last packet of a frame, so the
+    /// sender is never interrupted in the middle of a frame.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<&[u8]>, FrameError> {
+        let (header, chunk) = match packet.split_first() {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+        let end = header & FRAME_END != 0;
+
+        if header & FRAME_START != 0 {
+            self.reset();
+            self.active = true;
+        } else if !self.active {
+            return if end {
+                Err(FrameError::Unexpected)
+            } else {
+                Ok(None)
+            };
+        }
+
+        if self.len + chunk.len() > N {
+            self.overflow = true;
+        } else if !self.overflow {
+            self.buf[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+            self.len += chunk.len();
+        }
+
+        if !end {
+            Ok(None)
+        } else if self.overflow {
+            self.reset();
+            Err(FrameError::Overflow)
+        } else {
+            self.active = false;
+            Ok(Some(&self.buf[..self.len]))
+        }
+    }
+}
+
+impl<const N: usize> Default for Assembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serializes a message and hands it out packet by packet
+pub struct Fragmenter<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    pos: usize,
+    started: bool,
+}
+
+impl<const N: usize> Fragmenter<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            pos: 0,
+            started: false,
+        }
+    }
+
+    /// Serialize message as a new frame, dropping any unsent packets
+    pub fn load<T: Serialize>(&mut self, message: &T) -> postcard::Result<()> {
+        self.clear();
+        self.len = to_slice(message, &mut self.buf)?.len();
+        Ok(())
+    }
+
+    /// Drop any unsent packets
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.pos = 0;
+        self.started = false;
+    }
+
+    /// Has no packets to send
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.len
+    }
+
+    /// Write next packet into `packet`, returns its size
+    pub fn next_packet(&mut self, packet: &mut [u8]) -> Option<usize> {
+        if self.is_empty() || packet.len() <= FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        let chunk = (self.len - self.pos).min(packet.len() - FRAME_HEADER_SIZE);
+        let mut header = 0;
+        if !self.started {
+            header |= FRAME_START;
+            self.started = true;
+        }
+        if self.pos + chunk == self.len {
+            header |= FRAME_END;
+        }
+
+        packet[0] = header;
+        packet[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + chunk]
+            .copy_from_slice(&self.buf[self.pos..self.pos + chunk]);
+        self.pos += chunk;
+
+        Some(FRAME_HEADER_SIZE + chunk)
+    }
+}
+
+impl<const N: usize> Default for Fragmenter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{Block, Message};
+    use crate::usb::FRAME_MAX_SIZE;
+    use postcard::from_bytes;
+
+    #[test]
+    fn test_fragment_assemble_message() {
+        let message = Message::WriteBlock {
+            address: 0x2000_0000,
+            bytes: Block::from_slice(&[0x5a; 40]).unwrap(),
+        };
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        let mut assembler = Assembler::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(&message).unwrap();
+
+        // Smallest full speed packet to get many of them
+        let mut packet = [0u8; 8];
+        let mut packets = 0;
+        let mut frame = None;
+        while let Some(size) = fragmenter.next_packet(&mut packet) {
+            packets += 1;
+            if let Some(bytes) = assembler.push(&packet[..size]).unwrap() {
+                frame = Some(from_bytes::<Message>(bytes).unwrap());
+            }
+        }
+
+        assert!(packets > 1);
+        assert_eq!(Some(message), frame);
+    }
+}
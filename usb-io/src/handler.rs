@@ -0,0 +1,276 @@
+//! Backends executing memory accesses of host requests on the target.
+//!
+//! `UsbIoClass` checks alignment and the access policy of every request and
+//! then dispatches it to its [`TargetHandler`], [`RawMemory`] by default.
+//! Firmware can route some addresses elsewhere by wrapping `RawMemory`:
+//!
+//! ```
+//! use usb_io::{
+//!     handler::{RawMemory, TargetHandler},
+//!     message::{Data, DataSize, ErrorCode},
+//! };
+//!
+//! /// Software FIFO readable at a virtual address
+//! struct FifoHandler {
+//!     raw: RawMemory,
+//!     fifo: heapless::Deque<u8, 64>,
+//! }
+//!
+//! impl FifoHandler {
+//!     const FIFO: u32 = 0x6000_0000;
+//! }
+//!
+//! impl TargetHandler for FifoHandler {
+//!     fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+//!         match (address, size) {
+//!             (Self::FIFO, DataSize::U8) => Ok(Data::U8(self.fifo.pop_front().unwrap_or(0))),
+//!             (Self::FIFO, _) => Err(ErrorCode::UnsupportedSize),
+//!             _ => self.raw.read(address, size),
+//!         }
+//!     }
+//!
+//!     fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode> {
+//!         match address {
+//!             Self::FIFO => Err(ErrorCode::Forbidden),
+//!             _ => self.raw.write(address, data),
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{
+    message::{Capabilities, Data, DataSize, ErrorCode, Payload},
+    probe,
+};
+
+/// Custom command, writes its reply for the argument payload into the reply payload
+pub type Command<H> =
+    fn(handler: &mut H, args: &[u8], reply: &mut Payload) -> Result<(), ErrorCode>;
+
+/// Reset value of the core clock (internal RC oscillator of STM32F4)
+pub const DEFAULT_CORE_CLOCK: u32 = 16_000_000;
+
+/// Spans of a block of `len` bytes at `address` as `(start, len, size)`: the
+/// word aligned middle is accessed with word wide accesses, so blocks work
+/// on peripherals which only accept them and FIFOs pop once per word. The
+/// unaligned head and tail are accessed byte by byte, spans may be empty.
+pub fn block_spans(address: u32, len: usize) -> [(u32, usize, DataSize); 3] {
+    let head = ((address.wrapping_neg() & 3) as usize).min(len);
+    let words = (len - head) & !3;
+    let middle = address.wrapping_add(head as u32);
+    let tail = middle.wrapping_add(words as u32);
+    [
+        (address, head, DataSize::U8),
+        (middle, words, DataSize::U32),
+        (tail, len - head - words, DataSize::U8),
+    ]
+}
+
+/// Executes memory accesses of requests which passed alignment and policy checks
+pub trait TargetHandler {
+    fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode>;
+
+    fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode>;
+
+    /// Replace bits of `mask` with bits of `value`, handlers of hardware
+    /// registers should do it without being interrupted
+    fn modify(
+        &mut self,
+        address: u32,
+        size: DataSize,
+        mask: u32,
+        value: u32,
+    ) -> Result<(), ErrorCode> {
+        let current = match self.read(address, size)? {
+            Data::U8(value) => value as u32,
+            Data::U16(value) => value as u32,
+            Data::U32(value) => value,
+        };
+        let value = current & !mask | value & mask;
+        let data = match size {
+            DataSize::U8 => Data::U8(value as u8),
+            DataSize::U16 => Data::U16(value as u16),
+            DataSize::U32 => Data::U32(value),
+        };
+        self.write(address, data)
+    }
+
+    /// Read `bytes.len()` bytes, see [`block_spans`] for the access widths
+    fn read_bytes(&mut self, address: u32, bytes: &mut [u8]) -> Result<(), ErrorCode> {
+        let mut offset = 0;
+        for (start, len, size) in block_spans(address, bytes.len()) {
+            for (index, chunk) in bytes[offset..offset + len]
+                .chunks_exact_mut(size.bytes() as usize)
+                .enumerate()
+            {
+                let at = start.wrapping_add((index * chunk.len()) as u32);
+                match self.read(at, size)? {
+                    Data::U8(value) => chunk.copy_from_slice(&[value]),
+                    Data::U16(value) => chunk.copy_from_slice(&value.to_le_bytes()),
+                    Data::U32(value) => chunk.copy_from_slice(&value.to_le_bytes()),
+                }
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Write `bytes`, see [`block_spans`] for the access widths
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ErrorCode> {
+        let mut offset = 0;
+        for (start, len, size) in block_spans(address, bytes.len()) {
+            for (index, chunk) in bytes[offset..offset + len]
+                .chunks_exact(size.bytes() as usize)
+                .enumerate()
+            {
+                let at = start.wrapping_add((index * chunk.len()) as u32);
+                let data = match size {
+                    DataSize::U8 => Data::U8(chunk[0]),
+                    DataSize::U16 => Data::U16(u16::from_le_bytes([chunk[0], chunk[1]])),
+                    DataSize::U32 => Data::U32(u32::from_le_bytes(chunk.try_into().unwrap())),
+                };
+                self.write(at, data)?;
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Busy wait between operations of a batch
+    fn delay_us(&mut self, _us: u32) {}
+
+    /// `Capabilities::FEATURE_*` bits added to the ones of `UsbIoClass`
+    fn features(&self) -> u32 {
+        0
+    }
+
+    /// Unmask or mask interrupt line `irq` allowed to be forwarded
+    fn set_interrupt(&mut self, _irq: u8, _enabled: bool) -> Result<(), ErrorCode> {
+        Err(ErrorCode::Unsupported)
+    }
+}
+
+/// Raw address space of the running firmware, accessed with bus faults caught
+pub struct RawMemory {
+    /// Core clock frequency in Hz, used for cycle counting delays
+    pub core_clock: u32,
+}
+
+impl RawMemory {
+    pub const fn new(core_clock: u32) -> Self {
+        Self { core_clock }
+    }
+}
+
+impl Default for RawMemory {
+    fn default() -> Self {
+        Self::new(DEFAULT_CORE_CLOCK)
+    }
+}
+
+impl TargetHandler for RawMemory {
+    fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+        unsafe { probe::read(address, size) }
+    }
+
+    fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode> {
+        unsafe { probe::write(address, data) }
+    }
+
+    fn modify(
+        &mut self,
+        address: u32,
+        size: DataSize,
+        mask: u32,
+        value: u32,
+    ) -> Result<(), ErrorCode> {
+        unsafe { probe::modify(address, size, mask, value) }
+    }
+
+    fn read_bytes(&mut self, address: u32, bytes: &mut [u8]) -> Result<(), ErrorCode> {
+        unsafe {
+            probe::guarded(|| {
+                let mut offset = 0;
+                for (start, len, size) in block_spans(address, bytes.len()) {
+                    let span = &mut bytes[offset..offset + len];
+                    match size {
+                        DataSize::U32 => {
+                            for (index, word) in span.chunks_exact_mut(4).enumerate() {
+                                let at = start.wrapping_add(index as u32 * 4) as *const u32;
+                                word.copy_from_slice(&at.read_volatile().to_le_bytes());
+                            }
+                        }
+                        _ => {
+                            for (index, byte) in span.iter_mut().enumerate() {
+                                *byte =
+                                    (start.wrapping_add(index as u32) as *const u8).read_volatile();
+                            }
+                        }
+                    }
+                    offset += len;
+                }
+            })
+        }
+    }
+
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), ErrorCode> {
+        unsafe {
+            probe::guarded(|| {
+                let mut offset = 0;
+                for (start, len, size) in block_spans(address, bytes.len()) {
+                    let span = &bytes[offset..offset + len];
+                    match size {
+                        DataSize::U32 => {
+                            for (index, word) in span.chunks_exact(4).enumerate() {
+                                let at = start.wrapping_add(index as u32 * 4) as *mut u32;
+                                at.write_volatile(u32::from_le_bytes(word.try_into().unwrap()));
+                            }
+                        }
+                        _ => {
+                            for (index, byte) in span.iter().enumerate() {
+                                (start.wrapping_add(index as u32) as *mut u8).write_volatile(*byte);
+                            }
+                        }
+                    }
+                    offset += len;
+                }
+            })
+        }
+    }
+
+    /// Busy wait, the core clock is used for cycle counting on Cortex-M
+    fn delay_us(&mut self, us: u32) {
+        #[cfg(all(target_arch = "arm", target_os = "none"))]
+        cortex_m::asm::delay(us.saturating_mul(self.core_clock / 1_000_000));
+
+        #[cfg(all(not(all(target_arch = "arm", target_os = "none")), feature = "std"))]
+        std::thread::sleep(std::time::Duration::from_micros(us as u64));
+
+        #[cfg(not(any(all(target_arch = "arm", target_os = "none"), feature = "std")))]
+        let _ = (us, self.core_clock);
+    }
+
+    /// Unmask or mask the line in the NVIC, the firmware has to bind a
+    /// handler forwarding the interrupt to it
+    #[cfg(all(target_arch = "arm", target_os = "none"))]
+    fn set_interrupt(&mut self, irq: u8, enabled: bool) -> Result<(), ErrorCode> {
+        let nvic = cortex_m::peripheral::NVIC::PTR;
+        let (index, bit) = (irq as usize / 32, 1 << (irq % 32));
+        unsafe {
+            if enabled {
+                (*nvic).iser[index].write(bit);
+            } else {
+                (*nvic).icer[index].write(bit);
+            }
+        }
+        Ok(())
+    }
+
+    fn features(&self) -> u32 {
+        if probe::CATCHES_BUS_FAULTS {
+            Capabilities::FEATURE_BUS_FAULT
+        } else {
+            0
+        }
+    }
+}
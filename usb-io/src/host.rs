@@ -1,11 +1,42 @@
+/// Emit a `tracing` event at `$level` when the `tracing` feature is enabled
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    }};
+}
+
+#[cfg(feature = "async")]
+mod async_connection;
+mod batch;
 mod connection;
 mod device;
+mod error;
+mod events;
+mod filter;
+mod monitor;
+mod reconnect;
+pub mod sim;
+mod transport;
+
+#[cfg(feature = "async")]
+pub use self::async_connection::AsyncConnection;
 
 pub use self::{
+    batch::Batch,
     connection::Connection,
     device::{Device, Devices},
+    error::Error,
+    filter::DeviceFilter,
+    monitor::{DeviceEvent, DeviceMonitor},
+    reconnect::ReconnectingConnection,
+    transport::{Transport, UsbTransport},
 };
 
 use std::time::Duration;
 
 pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeout of a single blocking wait of the background threads of the event
+/// pump and the device monitor, bounds the time it takes to stop them
+const STOP_POLL_TIMEOUT: Duration = Duration::from_millis(100);
@@ -0,0 +1,289 @@
+//! `Connection` driven from async code.
+//!
+//! Transfers stay blocking and run on an I/O thread owned by the
+//! [`AsyncConnection`], futures only wait for their results. Requests of
+//! concurrent tasks are queued and sent back to back as soon as the previous
+//! response is read: the target holds off the next request until then, so
+//! there is at most one request on the wire.
+//!
+//! ```
+//! use usb_io::host::{sim::{SimMemory, SimTarget}, AsyncConnection, TIMEOUT};
+//! use usb_io::AsyncMemoryInterface;
+//!
+//! # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+//! let target = SimTarget::new(SimMemory::new().ram(0x2000_0000, 0x100));
+//! let connection = AsyncConnection::open(target, TIMEOUT).await.unwrap();
+//! connection.try_write32(0x2000_0000, 42).await.unwrap();
+//! assert_eq!(42, connection.try_read32(0x2000_0000).await.unwrap());
+//! # });
+//! ```
+
+use std::{
+    future::Future,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    host::{Connection, Device, Error, Transport, UsbTransport},
+    memory_interface::{AsyncMemoryInterface, MemoryInterface},
+    message::{Capabilities, Message},
+};
+
+type Job<T> = Box<dyn FnOnce(&Connection<T>) + Send>;
+
+/// Connection to USB-IO with requests executed on an I/O thread.
+///
+/// Requests run one at a time in the order they were queued. Although
+/// envelopes carry tags, a request is sent only after the response to the
+/// previous one was read, they are never pipelined.
+pub struct AsyncConnection<T: Transport = UsbTransport> {
+    jobs: Sender<Job<T>>,
+    capabilities: Capabilities,
+}
+
+impl AsyncConnection {
+    /// Open `device` on the I/O thread, opening resets the device and blocks
+    pub async fn open_device(device: Device, timeout: Duration) -> Result<Self, Error> {
+        Self::spawn(move || device.open(timeout)).await
+    }
+}
+
+impl<T: Transport + 'static> AsyncConnection<T> {
+    /// Move an open `connection` to an I/O thread of its own
+    pub fn new(connection: Connection<T>) -> Self {
+        let capabilities = *connection.capabilities();
+        let (jobs, queue) = mpsc::channel::<Job<T>>();
+        thread::spawn(move || queue.iter().for_each(|job| job(&connection)));
+
+        Self { jobs, capabilities }
+    }
+
+    /// Open connection over `transport`, the handshake runs on the I/O thread
+    pub async fn open(transport: T, timeout: Duration) -> Result<Self, Error> {
+        Self::spawn(move || Connection::open(transport, timeout)).await
+    }
+
+    /// Start the I/O thread with the connection made by `open`
+    async fn spawn(
+        open: impl FnOnce() -> Result<Connection<T>, Error> + Send + 'static,
+    ) -> Result<Self, Error> {
+        let (opened, result) = oneshot::channel();
+        let (jobs, queue) = mpsc::channel::<Job<T>>();
+
+        thread::spawn(move || {
+            let connection = match open() {
+                Ok(connection) => connection,
+                Err(err) => {
+                    let _ = opened.send(Err(err));
+                    return;
+                }
+            };
+            if opened.send(Ok(*connection.capabilities())).is_ok() {
+                queue.iter().for_each(|job| job(&connection));
+            }
+        });
+
+        let capabilities = result.await.map_err(|_| io_thread_gone())??;
+        Ok(Self { jobs, capabilities })
+    }
+
+    /// Capabilities reported by the target when the connection was opened
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Run `f` with the blocking connection on the I/O thread, after the
+    /// jobs queued before finished. Dropping the future does not cancel the job.
+    ///
+    /// Fails with `Error::Disconnected` if the I/O thread is gone.
+    pub fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection<T>) -> Result<R, Error> + Send + 'static,
+    ) -> impl Future<Output = Result<R, Error>> + Send + 'static {
+        let (done, result) = oneshot::channel();
+        let queued = self
+            .jobs
+            .send(Box::new(move |connection| {
+                let _ = done.send(f(connection));
+            }))
+            .is_ok();
+
+        async move {
+            match queued {
+                true => result.await.map_err(|_| io_thread_gone())?,
+                false => Err(io_thread_gone()),
+            }
+        }
+    }
+
+    /// Send a request and receive its response, see [`Connection::request`].
+    /// The request waits for the ones queued before to be answered.
+    pub async fn request(&self, message: Message) -> Result<Message, Error> {
+        self.run(move |connection| connection.request(message))
+            .await
+    }
+
+    /// Read `len` bytes of target memory starting from `address`, the
+    /// requests of the block are not interleaved with other requests
+    pub async fn read_block(&self, address: u32, len: usize) -> Result<Vec<u8>, Error> {
+        self.run(move |connection| {
+            let mut buf = vec![0; len];
+            connection.read_block(address, &mut buf)?;
+            Ok(buf)
+        })
+        .await
+    }
+
+    /// Write `bytes` into target memory starting from `address`, the
+    /// requests of the block are not interleaved with other requests
+    pub async fn write_block(&self, address: u32, bytes: Vec<u8>) -> Result<(), Error> {
+        self.run(move |connection| connection.write_block(address, &bytes))
+            .await
+    }
+
+    /// Wait until `value & mask == expected` for the word at `address`, see
+    /// [`Connection::wait_for32`]. Requests queued later wait until it finished.
+    pub async fn wait_for32(
+        &self,
+        address: u32,
+        mask: u32,
+        expected: u32,
+        timeout: Duration,
+    ) -> Result<u32, Error> {
+        self.run(move |connection| connection.wait_for32(address, mask, expected, timeout))
+            .await
+    }
+}
+
+/// Job was dropped without a result, the I/O thread panicked
+fn io_thread_gone() -> Error {
+    Error::Disconnected
+}
+
+/// Accesses are queued like [`AsyncConnection::request`], one at a time
+impl<T: Transport + 'static> AsyncMemoryInterface for AsyncConnection<T> {
+    type Error = Error;
+
+    fn try_read8(&self, address: u32) -> impl Future<Output = Result<u8, Error>> + Send {
+        self.run(move |connection| connection.try_read8(address))
+    }
+
+    fn try_read16(&self, address: u32) -> impl Future<Output = Result<u16, Error>> + Send {
+        self.run(move |connection| connection.try_read16(address))
+    }
+
+    fn try_read32(&self, address: u32) -> impl Future<Output = Result<u32, Error>> + Send {
+        self.run(move |connection| connection.try_read32(address))
+    }
+
+    fn try_write8(
+        &self,
+        address: u32,
+        value: u8,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_write8(address, value))
+    }
+
+    fn try_write16(
+        &self,
+        address: u32,
+        value: u16,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_write16(address, value))
+    }
+
+    fn try_write32(
+        &self,
+        address: u32,
+        value: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_write32(address, value))
+    }
+
+    fn try_modify32(
+        &self,
+        address: u32,
+        mask: u32,
+        value: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_modify32(address, mask, value))
+    }
+
+    fn try_set_bits32(
+        &self,
+        address: u32,
+        bits: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_set_bits32(address, bits))
+    }
+
+    fn try_clear_bits32(
+        &self,
+        address: u32,
+        bits: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.run(move |connection| connection.try_clear_bits32(address, bits))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::host::{
+        sim::{SimMemory, SimTarget},
+        TIMEOUT,
+    };
+
+    const RAM: u32 = 0x2000_0000;
+
+    #[tokio::test]
+    async fn test_async_connection() {
+        let target = SimTarget::new(SimMemory::new().ram(RAM, 0x100));
+        let connection = AsyncConnection::open(target, TIMEOUT).await.unwrap();
+
+        // Requests of concurrent tasks are queued on the I/O thread
+        let (first, second) = tokio::join!(
+            connection.try_write32(RAM, 0x1234_5678),
+            connection.try_write16(RAM + 4, 0xbeef),
+        );
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(0x1234_5678, connection.try_read32(RAM).await.unwrap());
+        assert_eq!(0xbeef, connection.try_read16(RAM + 4).await.unwrap());
+
+        connection.try_set_bits32(RAM, 0xff).await.unwrap();
+        assert_eq!(0x1234_56ff, connection.try_read32(RAM).await.unwrap());
+
+        connection
+            .write_block(RAM + 8, vec![1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![1, 2, 3],
+            connection.read_block(RAM + 8, 3).await.unwrap()
+        );
+        assert_eq!(
+            Message::Pong,
+            connection.request(Message::Ping).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_io_thread_gone() {
+        let target = SimTarget::new(SimMemory::new().ram(RAM, 0x100));
+        let connection = AsyncConnection::open(target, TIMEOUT).await.unwrap();
+
+        let result = connection
+            .run(|_| -> Result<(), Error> { panic!("job failed") })
+            .await;
+        assert!(matches!(result, Err(Error::Disconnected)));
+        // Jobs queued afterwards are not run
+        assert!(matches!(
+            connection.try_read32(RAM).await,
+            Err(Error::Disconnected)
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use std::{ops::Range, thread, time::Duration};
+
+use crate::{
+    host::{Connection, Error, Transport, UsbTransport},
+    memory_interface::MemoryInterface,
+    message::{Capabilities, Data, DataSize, ErrorCode, Message, Operation, Operations},
+    usb::{BATCH_MAX_DELAY_US, BATCH_MAX_OPS},
+};
+
+/// List of operations executed by the target in order, built with
+/// [`Connection::batch`]
+pub struct Batch<'a, T: Transport = UsbTransport> {
+    connection: &'a Connection<T>,
+    operations: Vec<Operation>,
+}
+
+impl<'a, T: Transport> Batch<'a, T> {
+    pub(super) fn new(connection: &'a Connection<T>) -> Self {
+        Self {
+            connection,
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn write8(self, address: u32, value: u8) -> Self {
+        self.push(Operation::Set(address, Data::U8(value)))
+    }
+
+    pub fn write16(self, address: u32, value: u16) -> Self {
+        self.push(Operation::Set(address, Data::U16(value)))
+    }
+
+    pub fn write32(self, address: u32, value: u32) -> Self {
+        self.push(Operation::Set(address, Data::U32(value)))
+    }
+
+    pub fn read8(self, address: u32) -> Self {
+        self.push(Operation::Get(address, DataSize::U8))
+    }
+
+    pub fn read16(self, address: u32) -> Self {
+        self.push(Operation::Get(address, DataSize::U16))
+    }
+
+    pub fn read32(self, address: u32) -> Self {
+        self.push(Operation::Get(address, DataSize::U32))
+    }
+
+    /// Replace bits of `mask` with bits of `value`
+    pub fn modify32(self, address: u32, mask: u32, value: u32) -> Self {
+        self.push(Operation::Modify {
+            address,
+            mask,
+            value,
+            width: DataSize::U32,
+        })
+    }
+
+    /// Busy wait on the target, long delays are split into several operations
+    /// sent in separate `Batch` messages
+    pub fn delay_us(mut self, us: u32) -> Self {
+        let mut remaining = us;
+        while remaining > BATCH_MAX_DELAY_US {
+            self = self.push(Operation::Delay(BATCH_MAX_DELAY_US));
+            remaining -= BATCH_MAX_DELAY_US;
+        }
+        self.push(Operation::Delay(remaining))
+    }
+
+    fn push(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Execute operations, returns values of all reads in order.
+    ///
+    /// Operations are sent in as many `Batch` messages as needed, a failed
+    /// operation is reported as `Error::Batch` with its index in this batch
+    /// and the remaining operations are not executed.
+    pub fn execute(self) -> Result<Vec<Data>, Error> {
+        if !self
+            .connection
+            .capabilities()
+            .supports(Capabilities::FEATURE_BATCH)
+        {
+            return self.execute_sequential();
+        }
+
+        let mut results = Vec::new();
+
+        for chunk in split(&self.operations) {
+            // Chunks are never longer than the batch capacity
+            let operations = Operations::from_slice(&self.operations[chunk.clone()]).unwrap();
+
+            match self.connection.request(Message::Batch(operations))? {
+                Message::BatchResult(data) => results.extend(data),
+                Message::BatchError { index, error } => {
+                    return Err(Error::Batch {
+                        index: chunk.start + index as usize,
+                        code: error,
+                    })
+                }
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Fallback for firmware without batch support
+    fn execute_sequential(self) -> Result<Vec<Data>, Error> {
+        let connection = self.connection;
+        let mut results = Vec::new();
+
+        for (index, operation) in self.operations.into_iter().enumerate() {
+            let result = match operation {
+                Operation::Set(address, data) => {
+                    match connection.request(Message::Set(address, data)) {
+                        Ok(Message::Ack) => Ok(()),
+                        Ok(response) => Err(Error::UnexpectedResponse(Box::new(response))),
+                        Err(err) => Err(err),
+                    }
+                }
+                Operation::Get(address, size) => {
+                    match connection.request(Message::Get(address, size)) {
+                        Ok(Message::Data(data)) if data.size() == size => {
+                            results.push(data);
+                            Ok(())
+                        }
+                        Ok(response) => Err(Error::UnexpectedResponse(Box::new(response))),
+                        Err(err) => Err(err),
+                    }
+                }
+                Operation::Modify {
+                    address,
+                    mask,
+                    value,
+                    width: DataSize::U32,
+                } => connection.try_modify32(address, mask, value),
+                Operation::Modify { .. } => Err(Error::Target(ErrorCode::UnsupportedSize)),
+                Operation::Delay(us) => {
+                    thread::sleep(Duration::from_micros(us as u64));
+                    Ok(())
+                }
+            };
+
+            match result {
+                Err(Error::Target(code)) => return Err(Error::Batch { index, code }),
+                Err(err) => return Err(err),
+                Ok(()) => (),
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+/// Split `operations` into chunks sent as one `Batch` message each, within
+/// the operation capacity and the delay budget of a batch
+fn split(operations: &[Operation]) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < operations.len() {
+        let mut end = start;
+        let mut delayed_us = 0;
+        while end < operations.len() && end - start < BATCH_MAX_OPS {
+            if let Operation::Delay(us) = operations[end] {
+                if end > start && delayed_us + us > BATCH_MAX_DELAY_US {
+                    break;
+                }
+                delayed_us += us;
+            }
+            end += 1;
+        }
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let read = Operation::Get(0x2000_0000, DataSize::U32);
+        let mut operations = vec![read; BATCH_MAX_OPS + 1];
+        assert_eq!(
+            vec![0..BATCH_MAX_OPS, BATCH_MAX_OPS..BATCH_MAX_OPS + 1],
+            split(&operations)
+        );
+
+        // Delays of a chunk stay within the budget
+        let half = Operation::Delay(BATCH_MAX_DELAY_US / 2);
+        operations = vec![
+            read,
+            half,
+            read,
+            half,
+            half,
+            read,
+            Operation::Delay(BATCH_MAX_DELAY_US),
+        ];
+        assert_eq!(vec![0..4, 4..6, 6..7], split(&operations));
+    }
+}
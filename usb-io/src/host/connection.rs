@@ -1,165 +1,818 @@
 use postcard::{from_bytes, to_slice};
-use std::{sync::Mutex, time::Duration};
-
-use rusb::{Context, DeviceHandle};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    sync::{mpsc::Receiver, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    host::Device,
+    frame::{Assembler, Fragmenter},
+    host::{events::EventPump, Batch, Device, Error, Transport, UsbTransport},
     memory_interface::MemoryInterface,
-    message::{Data, DataSize, Message},
-    usb::{MESSAGE_MAX_SIZE, USB_IO_IN_ENDPOINT, USB_IO_OUT_ENDPOINT},
+    message::{
+        Block, Capabilities, Data, DataSize, Envelope, ErrorCode, Event, Interrupt, Message,
+        Payload, Status, PROTOCOL_VERSION,
+    },
+    usb::{
+        BLOCK_MAX_SIZE, FRAME_MAX_SIZE, PACKET_MAX_SIZE, PAYLOAD_MAX_SIZE, REQUEST_REBOOT,
+        REQUEST_RESET_PIPE, REQUEST_STATUS, REQUEST_VERSION,
+    },
 };
 
-/// Number of times to retry a bulk message receive operation before giving up
-const MAX_RECV_RETRIES: usize = 3;
+/// Number of lingering messages to drain when the connection is opened
+const MAX_LINGERING_MESSAGES: usize = 3;
+
+/// Timeout of receiving lingering packets after a pipe reset
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Number of responses with foreign tags to skip while waiting for a response
+const MAX_STALE_RESPONSES: usize = 8;
 
-/// Connection to USB-IO via USB
-pub struct Connection {
-    /// Handle to the underlying USB device
-    handle: Mutex<DeviceHandle<Context>>,
+/// Connection to USB-IO over a `Transport`, USB bulk endpoints by default
+pub struct Connection<T: Transport = UsbTransport> {
+    /// Packet link to the target, shared with the event pump
+    transport: Arc<T>,
 
-    /// USB-IO device this connection is connected to
-    device: Device,
+    /// Held while packets of a message are sent or received
+    io: Mutex<()>,
 
     /// Timeout for reading from / writing to the USB-IO
     timeout: Duration,
+
+    /// Size of packets messages are split into
+    packet_size: usize,
+
+    /// Tag of the last request, locked for the whole request / response exchange
+    tag: Mutex<u16>,
+
+    /// Capabilities reported by the target
+    capabilities: Capabilities,
+
+    /// Reader of target events, started by the first subscriber
+    events: Mutex<Option<EventPump>>,
 }
 
 impl Connection {
-    /// Create a new YubiHSM device from a rusb device
-    pub(super) fn create(device: Device, timeout: Duration) -> Result<Self, rusb::Error> {
-        let handle = device.open_handle()?;
+    /// Borrow the `Device` for this connection
+    pub fn device(&self) -> &Device {
+        self.transport.device()
+    }
+}
+
+impl<T: Transport> Connection<T> {
+    /// Open connection over `transport` and perform the handshake, targets
+    /// speaking another version of the protocol are rejected with `Error::Protocol`
+    pub fn open(transport: T, timeout: Duration) -> Result<Self, Error> {
+        let packet_size = transport.max_packet_size().min(PACKET_MAX_SIZE);
 
-        let connection = Self {
-            device,
+        let mut connection = Self {
+            transport: Arc::new(transport),
+            io: Mutex::new(()),
             timeout,
-            handle: Mutex::new(handle),
+            packet_size,
+            tag: Mutex::new(Envelope::UNTAGGED),
+            capabilities: Capabilities::basic(packet_size as u16),
+            events: Mutex::new(None),
         };
 
         // Clear any lingering messages
-        for _ in 0..MAX_RECV_RETRIES {
+        for _ in 0..MAX_LINGERING_MESSAGES {
             if connection.recv_message().is_err() {
                 break;
             }
         }
 
+        connection.capabilities = match connection.request(Message::Hello) {
+            Ok(Message::Capabilities(capabilities)) => capabilities,
+            Ok(response) => return Err(Error::UnexpectedResponse(Box::new(response))),
+            Err(err) => return Err(err),
+        };
+        if connection.capabilities.protocol_version != PROTOCOL_VERSION {
+            log!(
+                warn,
+                version = connection.capabilities.protocol_version,
+                expected = PROTOCOL_VERSION,
+                "incompatible protocol version"
+            );
+            return Err(Error::Protocol);
+        }
+
         Ok(connection)
     }
 
-    /// Borrow the `Device` for this connection
-    pub fn device(&self) -> &Device {
-        &self.device
-    }
-
-    /// Write a bulk message to the USB-IO
-    pub fn send_message(&self, message: Message) -> Result<usize, rusb::Error> {
-        let mut buf = [0; MESSAGE_MAX_SIZE as usize];
-        let slice = to_slice(&message, &mut buf).unwrap();
-        let nbytes =
-            self.handle
-                .lock()
-                .unwrap()
-                .write_bulk(USB_IO_OUT_ENDPOINT, slice, self.timeout)?;
-
-        if slice.len() == nbytes {
-            Ok(nbytes)
-        } else {
-            Err(rusb::Error::Other)
-        }
-    }
-
-    /// Receive a message
-    pub fn recv_message(&self) -> Result<Message, rusb::Error> {
-        // Allocate a buffer which is the maximum size we expect to receive
-        let mut buf = [0; MESSAGE_MAX_SIZE as usize];
-
-        for attempts_remaining in (0..MAX_RECV_RETRIES).rev() {
-            match self
-                .handle
-                .lock()
-                .unwrap()
-                .read_bulk(USB_IO_IN_ENDPOINT, &mut buf, self.timeout)
-            {
-                Ok(_) => {
-                    if let Ok(message) = from_bytes(&buf) {
-                        return Ok(message);
-                    } else {
-                        return Err(rusb::Error::Other);
-                    }
-                }
+    /// Capabilities reported by the target when the connection was opened
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Size of packets messages are split into
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Borrow the transport of this connection
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Write a message to the USB-IO, split into as many packets as needed
+    pub fn send_message(&self, envelope: &Envelope) -> Result<usize, Error> {
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(envelope).map_err(Error::Encode)?;
 
-                // Sometimes I/O errors occur sporadically. When this happens,
-                // retry the read for `MAX_RECV_RETRIES` attempts
-                Err(rusb::Error::Io) => {
-                    println!(
-                        "I/O error during USB bulk message receive, retrying ({} attempts remaining)",
-                        attempts_remaining
-                    );
+        let _io = self.io.lock().unwrap();
+        let mut buf = [0; PACKET_MAX_SIZE];
+        let buf = &mut buf[..self.packet_size];
+        let mut total = 0;
+
+        while let Some(size) = fragmenter.next_packet(buf) {
+            self.transport.send_packet(&buf[..size], self.timeout)?;
+            total += size;
+        }
+
+        Ok(total)
+    }
+
+    /// Receive a message, reassembling it from packets
+    pub fn recv_message(&self) -> Result<Envelope, Error> {
+        self.recv_message_timeout(self.timeout)
+    }
+
+    /// Receive a message, waiting up to `timeout` for each packet.
+    ///
+    /// Broken frames, e.g. the rest of a frame cut by an earlier timeout, are
+    /// dropped as long as `timeout` has not passed since the call.
+    fn recv_message_timeout(&self, timeout: Duration) -> Result<Envelope, Error> {
+        let mut assembler = Assembler::<FRAME_MAX_SIZE>::new();
+        let deadline = Instant::now() + timeout;
+
+        let _io = self.io.lock().unwrap();
+        let mut buf = [0; PACKET_MAX_SIZE];
+        // Single packet, see `Transport::recv_packet`
+        let buf = &mut buf[..self.packet_size];
+
+        loop {
+            let size = self.transport.recv_packet(buf, timeout)?;
+
+            match assembler.push(&buf[..size]) {
+                Ok(Some(frame)) => return from_bytes(frame).map_err(Error::Decode),
+                Ok(None) => continue,
+                // The assembler is reset and waits for the next frame start
+                Err(_) if Instant::now() < deadline => {
+                    log!(debug, "dropping broken frame");
                     continue;
                 }
-                // All other errors we return immediately
-                Err(err) => return Err(err),
+                Err(_) => return Err(Error::Protocol),
             }
         }
-        Err(rusb::Error::Other)
     }
 
-    pub fn request(&self, message: Message) -> Result<Message, rusb::Error> {
-        self.send_message(message)?;
-        self.recv_message()
+    /// Send a request and receive its response, target errors are returned as `Error::Target`.
+    ///
+    /// Responses carrying tags of earlier requests are stale and skipped.
+    pub fn request(&self, message: Message) -> Result<Message, Error> {
+        self.request_timeout(message, self.timeout)
+    }
+
+    /// Send a request and wait up to `timeout` for its response
+    fn request_timeout(&self, message: Message, timeout: Duration) -> Result<Message, Error> {
+        let mut tag = self.tag.lock().unwrap();
+        *tag = next_tag(*tag);
+
+        #[cfg(feature = "tracing")]
+        let (kind, started) = (message.kind(), Instant::now());
+        self.send_message(&Envelope::new(*tag, message))?;
+
+        for _ in 0..MAX_STALE_RESPONSES {
+            let response = self.recv_message_timeout(timeout)?;
+
+            // Untagged errors are answers to requests the target failed to decode,
+            // and as requests are not pipelined it is the current one
+            let untagged_error =
+                response.tag == Envelope::UNTAGGED && matches!(response.message, Message::Error(_));
+
+            if response.tag != *tag && !untagged_error {
+                log!(
+                    debug,
+                    tag = response.tag,
+                    expected = *tag,
+                    "skipping stale response"
+                );
+                continue;
+            }
+
+            log!(
+                trace,
+                tag = *tag,
+                request = kind,
+                response = response.message.kind(),
+                latency_us = started.elapsed().as_micros() as u64,
+                "request completed"
+            );
+
+            return match response.message {
+                Message::Error(code) => Err(Error::Target(code)),
+                message => Ok(message),
+            };
+        }
+        Err(Error::Protocol)
+    }
+
+    /// State of the bulk pipe of the target, queried out of band
+    pub fn status(&self) -> Result<Status, Error> {
+        let mut buf = [0; 16];
+        let size = self
+            .transport
+            .control_in(REQUEST_STATUS, &mut buf, self.timeout)?;
+        from_bytes(&buf[..size]).map_err(Error::Decode)
+    }
+
+    /// Wire protocol version of the target, queried out of band
+    pub fn protocol_version(&self) -> Result<u16, Error> {
+        let mut buf = [0; 2];
+        match self
+            .transport
+            .control_in(REQUEST_VERSION, &mut buf, self.timeout)?
+        {
+            2 => Ok(u16::from_le_bytes(buf)),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Recover a wedged bulk pipe: the target drops the request and response
+    /// in progress and stops holding off requests, the halt of the host side
+    /// endpoints is cleared and lingering packets are drained
+    pub fn reset_pipe(&self) -> Result<(), Error> {
+        // No request is in progress while the tag is held
+        let _tag = self.tag.lock().unwrap();
+        self.transport
+            .control_out(REQUEST_RESET_PIPE, self.timeout)?;
+        self.transport.clear_halt()?;
+
+        for _ in 0..MAX_LINGERING_MESSAGES {
+            if self.recv_message_timeout(PIPE_DRAIN_TIMEOUT).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the firmware to reboot, the device disconnects and enumerates
+    /// again, so the connection is of no use afterwards
+    pub fn reboot(&self) -> Result<(), Error> {
+        self.transport.control_out(REQUEST_REBOOT, self.timeout)
     }
 
     pub fn ready_to_use(&self) -> bool {
-        if let Ok(Message::Pong) = self.request(Message::Ping) {
-            true
-        } else {
-            false
+        matches!(self.request(Message::Ping), Ok(Message::Pong))
+    }
+
+    /// Wait until `value & mask == expected` for the word at `address`, the
+    /// condition is polled by the target. Returns the last read value.
+    pub fn wait_for32(
+        &self,
+        address: u32,
+        mask: u32,
+        expected: u32,
+        timeout: Duration,
+    ) -> Result<u32, Error> {
+        let timeout_us = u32::try_from(timeout.as_micros()).unwrap_or(u32::MAX);
+        let message = Message::WaitFor {
+            address,
+            mask,
+            expected,
+            timeout_us,
+        };
+
+        match self.request_timeout(message, self.timeout + timeout)? {
+            Message::Data(Data::U32(value)) => Ok(value),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    /// Start a list of operations executed by the target in one exchange
+    pub fn batch(&self) -> Batch<'_, T> {
+        Batch::new(self)
+    }
+
+    /// Run custom command `id` registered by the firmware, arguments and
+    /// reply are serialized with postcard
+    pub fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        id: u16,
+        request: &Req,
+    ) -> Result<Resp, Error> {
+        let mut buf = [0; PAYLOAD_MAX_SIZE];
+        let args = to_slice(request, &mut buf).map_err(Error::Encode)?;
+        // The buffer is as large as the payload capacity
+        let payload = Payload::from_slice(args).unwrap();
+
+        match self.request(Message::Custom { id, payload })? {
+            Message::Custom {
+                id: reply_id,
+                payload,
+            } if reply_id == id => from_bytes(&payload).map_err(Error::Decode),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    /// Forward occurrences of interrupt line `irq` as `Event::Interrupt`, the
+    /// firmware has to allow forwarding of the line
+    pub fn enable_interrupt(&self, irq: u8) -> Result<(), Error> {
+        match self.request(Message::EnableInterrupt(irq))? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    /// Stop forwarding interrupt line `irq`
+    pub fn disable_interrupt(&self, irq: u8) -> Result<(), Error> {
+        match self.request(Message::DisableInterrupt(irq))? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
         }
     }
+
+    /// Read `buf.len()` bytes of target memory starting from `address`
+    pub fn read_block(&self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+        check_block(address, buf.len())?;
+
+        for (index, chunk) in buf.chunks_mut(BLOCK_MAX_SIZE).enumerate() {
+            // Block does not wrap around, checked above
+            let address = address + (index * BLOCK_MAX_SIZE) as u32;
+            let len = chunk.len() as u16;
+
+            match self.request(Message::ReadBlock { address, len })? {
+                Message::Block(bytes) if bytes.len() == chunk.len() => {
+                    chunk.copy_from_slice(&bytes)
+                }
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` into target memory starting from `address`
+    pub fn write_block(&self, address: u32, bytes: &[u8]) -> Result<(), Error> {
+        check_block(address, bytes.len())?;
+
+        for (index, chunk) in bytes.chunks(BLOCK_MAX_SIZE).enumerate() {
+            // Block does not wrap around, checked above
+            let address = address + (index * BLOCK_MAX_SIZE) as u32;
+            // Chunks are never longer than the block capacity
+            let bytes = Block::from_slice(chunk).unwrap();
+
+            match self.request(Message::WriteBlock { address, bytes })? {
+                Message::Ack => {}
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            }
+        }
+        Ok(())
+    }
 }
 
-impl MemoryInterface for Connection {
-    type Error = rusb::Error;
+impl<T: Transport> MemoryInterface for Connection<T> {
+    type Error = Error;
 
     fn try_read8(&self, address: u32) -> Result<u8, Self::Error> {
-        let data = self.request(Message::Get(address, DataSize::U8))?;
-        if let Message::Data(Data::U8(data)) = data {
-            Ok(data)
-        } else {
-            Err(rusb::Error::Other)
+        match self.request(Message::Get(address, DataSize::U8))? {
+            Message::Data(Data::U8(data)) => Ok(data),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
         }
     }
 
     fn try_read16(&self, address: u32) -> Result<u16, Self::Error> {
-        let data = self.request(Message::Get(address, DataSize::U16))?;
-        if let Message::Data(Data::U16(data)) = data {
-            Ok(data)
-        } else {
-            Err(rusb::Error::Other)
+        match self.request(Message::Get(address, DataSize::U16))? {
+            Message::Data(Data::U16(data)) => Ok(data),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
         }
     }
 
     fn try_read32(&self, address: u32) -> Result<u32, Self::Error> {
-        let data = self.request(Message::Get(address, DataSize::U32))?;
-        if let Message::Data(Data::U32(data)) = data {
-            Ok(data)
-        } else {
-            Err(rusb::Error::Other)
+        match self.request(Message::Get(address, DataSize::U32))? {
+            Message::Data(Data::U32(data)) => Ok(data),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
         }
     }
 
     fn try_write8(&self, address: u32, value: u8) -> Result<(), Self::Error> {
-        self.request(Message::Set(address, Data::U8(value)))?;
-        Ok(())
+        match self.request(Message::Set(address, Data::U8(value)))? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
     }
 
     fn try_write16(&self, address: u32, value: u16) -> Result<(), Self::Error> {
-        self.request(Message::Set(address, Data::U16(value)))?;
-        Ok(())
+        match self.request(Message::Set(address, Data::U16(value)))? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
     }
 
     fn try_write32(&self, address: u32, value: u32) -> Result<(), Self::Error> {
-        self.request(Message::Set(address, Data::U32(value)))?;
+        match self.request(Message::Set(address, Data::U32(value)))? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    fn try_modify32(&self, address: u32, mask: u32, value: u32) -> Result<(), Self::Error> {
+        if !self.capabilities.supports(Capabilities::FEATURE_MODIFY) {
+            let current = self.try_read32(address)?;
+            return self.try_write32(address, current & !mask | value & mask);
+        }
+
+        let width = DataSize::U32;
+        match self.request(Message::Modify {
+            address,
+            mask,
+            value,
+            width,
+        })? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    fn try_set_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        if !self.capabilities.supports(Capabilities::FEATURE_MODIFY) {
+            return self.try_modify32(address, bits, bits);
+        }
+
+        let width = DataSize::U32;
+        match self.request(Message::SetBits {
+            address,
+            bits,
+            width,
+        })? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    fn try_clear_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        if !self.capabilities.supports(Capabilities::FEATURE_MODIFY) {
+            return self.try_modify32(address, bits, 0);
+        }
+
+        let width = DataSize::U32;
+        match self.request(Message::ClearBits {
+            address,
+            bits,
+            width,
+        })? {
+            Message::Ack => Ok(()),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+}
+
+impl<T: Transport + 'static> Connection<T> {
+    /// Receive events pushed by the target, events are read by a background
+    /// thread started by the first subscriber.
+    ///
+    /// The receiver is disconnected when the transport fails.
+    pub fn subscribe(&self) -> Result<Receiver<Event>, Error> {
+        if !self.capabilities.supports(Capabilities::FEATURE_EVENTS) {
+            return Err(Error::Target(ErrorCode::Unsupported));
+        }
+
+        let mut events = self.events.lock().unwrap();
+        // Restart the pump after a transport failure
+        if events.as_ref().is_none_or(EventPump::is_finished) {
+            *events = Some(EventPump::start(self.transport.clone(), self.packet_size));
+        }
+        Ok(events.as_ref().unwrap().subscribe())
+    }
+
+    /// Call `f` for every event pushed by the target, on a thread of its own
+    pub fn on_event(&self, mut f: impl FnMut(Event) + Send + 'static) -> Result<(), Error> {
+        let receiver = self.subscribe()?;
+        thread::spawn(move || receiver.iter().for_each(&mut f));
+        Ok(())
+    }
+
+    /// Enable forwarding of interrupt line `irq` and call `f` for every
+    /// occurrence, on a thread of its own
+    pub fn on_interrupt(
+        &self,
+        irq: u8,
+        mut f: impl FnMut(Interrupt) + Send + 'static,
+    ) -> Result<(), Error> {
+        if !self.capabilities.supports(Capabilities::FEATURE_INTERRUPTS) {
+            return Err(Error::Target(ErrorCode::Unsupported));
+        }
+
+        // Subscribe first to not miss occurrences right after enabling
+        let receiver = self.subscribe()?;
+        self.enable_interrupt(irq)?;
+        thread::spawn(move || {
+            for event in receiver {
+                match event {
+                    Event::Interrupt(interrupt) if interrupt.irq == irq => f(interrupt),
+                    _ => (),
+                }
+            }
+        });
         Ok(())
     }
 }
+
+/// Block of `len` bytes at `address` may end at the top of the address
+/// space but not wrap around
+fn check_block(address: u32, len: usize) -> Result<(), Error> {
+    let last = u32::try_from(len.saturating_sub(1)).map_err(|_| Error::OutOfRange)?;
+    address.checked_add(last).ok_or(Error::OutOfRange)?;
+    Ok(())
+}
+
+/// Tag following `tag`, never `Envelope::UNTAGGED`
+fn next_tag(tag: u16) -> u16 {
+    match tag.wrapping_add(1) {
+        Envelope::UNTAGGED => Envelope::UNTAGGED + 1,
+        tag => tag,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{frame::FRAME_END, host::TIMEOUT, message::ErrorCode};
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// Answers every request right away, 32 bit reads return the address.
+    ///
+    /// Like libusb, a read runs on into the next packet until a short packet
+    /// arrives or the buffer is full.
+    #[derive(Default)]
+    struct EchoTransport {
+        rx: Mutex<Assembler<FRAME_MAX_SIZE>>,
+        tx: Mutex<VecDeque<Vec<u8>>>,
+        events: Mutex<VecDeque<Vec<u8>>>,
+        /// Packets received ahead of the next response
+        injected: Mutex<Vec<Vec<u8>>>,
+        /// Every response is sent twice
+        duplicate: bool,
+        /// Bulk endpoints are stalled until the halt is cleared
+        halted: AtomicBool,
+        /// Target speaks another version of the protocol
+        incompatible: bool,
+    }
+
+    impl EchoTransport {
+        fn inject(&self, envelope: &Envelope) {
+            self.injected.lock().unwrap().extend(packets(envelope));
+        }
+    }
+
+    /// Packets of `message` as sent by the target
+    fn packets(message: &impl Serialize) -> Vec<Vec<u8>> {
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(message).unwrap();
+        let mut buf = [0; 8];
+        let mut packets = vec![];
+        while let Some(size) = fragmenter.next_packet(&mut buf) {
+            packets.push(buf[..size].to_vec());
+        }
+        packets
+    }
+
+    /// Read queued packets into `buf` as a single transfer
+    fn read_joined(queue: &Mutex<VecDeque<Vec<u8>>>, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut queue = queue.lock().unwrap();
+        let mut len = 0;
+        while let Some(packet) = queue.front() {
+            if len + packet.len() > buf.len() {
+                break;
+            }
+            buf[len..len + packet.len()].copy_from_slice(packet);
+            len += packet.len();
+            let short = packet.len() < 8;
+            queue.pop_front();
+            if short {
+                break;
+            }
+        }
+        match len {
+            0 => Err(Error::Timeout),
+            len => Ok(len),
+        }
+    }
+
+    impl Transport for EchoTransport {
+        fn max_packet_size(&self) -> usize {
+            8
+        }
+
+        fn send_packet(&self, packet: &[u8], _timeout: Duration) -> Result<(), Error> {
+            if self.halted.load(Ordering::Relaxed) {
+                return Err(Error::Usb(rusb::Error::Pipe));
+            }
+            let mut rx = self.rx.lock().unwrap();
+            let request: Envelope = match rx.push(packet).unwrap() {
+                Some(frame) => from_bytes(frame).unwrap(),
+                None => return Ok(()),
+            };
+            // Requests which cannot be decoded are answered untagged
+            let tag = match request.message {
+                Message::Nop => Envelope::UNTAGGED,
+                _ => request.tag,
+            };
+            let response = match request.message {
+                Message::Hello => Message::Capabilities(Capabilities {
+                    protocol_version: PROTOCOL_VERSION + self.incompatible as u16,
+                    features: Capabilities::FEATURE_EVENTS,
+                    ..Capabilities::basic(8)
+                }),
+                Message::Nop => Message::Error(ErrorCode::Decode),
+                Message::Get(address, DataSize::U32) => Message::Data(Data::U32(address)),
+                Message::ReadBlock { len, .. } => {
+                    let bytes = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+                    Message::Block(Block::from_slice(&bytes).unwrap())
+                }
+                _ => Message::Ack,
+            };
+
+            let mut tx = self.tx.lock().unwrap();
+            tx.extend(self.injected.lock().unwrap().drain(..));
+            let response = packets(&Envelope::new(tag, response));
+            tx.extend(response.iter().cloned());
+            if self.duplicate {
+                tx.extend(response);
+            }
+            Ok(())
+        }
+
+        fn recv_packet(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+            read_joined(&self.tx, buf)
+        }
+
+        fn recv_event_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+            read_joined(&self.events, buf).inspect_err(|_| thread::sleep(timeout))
+        }
+
+        fn control_out(&self, request: u8, _timeout: Duration) -> Result<(), Error> {
+            match request {
+                REQUEST_RESET_PIPE => Ok(()),
+                _ => Err(Error::Unsupported),
+            }
+        }
+
+        fn clear_halt(&self) -> Result<(), Error> {
+            self.halted.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_incompatible_protocol_version() {
+        let transport = EchoTransport {
+            incompatible: true,
+            ..EchoTransport::default()
+        };
+        assert!(matches!(
+            Connection::open(transport, Duration::ZERO),
+            Err(Error::Protocol)
+        ));
+    }
+
+    #[test]
+    fn test_reset_pipe() {
+        let connection = Connection::open(EchoTransport::default(), Duration::ZERO).unwrap();
+
+        // Endpoint stalled by the target stays halted on the host side
+        connection.transport().halted.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            connection.request(Message::Ping),
+            Err(Error::Usb(rusb::Error::Pipe))
+        ));
+        connection.reset_pipe().unwrap();
+        assert!(connection.try_write32(0x2000_0010, 1).is_ok());
+    }
+
+    #[test]
+    fn test_connection_over_transport() {
+        let connection = Connection::open(EchoTransport::default(), Duration::ZERO).unwrap();
+
+        assert_eq!(8, connection.capabilities().max_packet_size);
+        assert_eq!(
+            Ok(0x2000_0010),
+            connection.try_read32(0x2000_0010).map_err(|_| ())
+        );
+        assert!(connection.try_write32(0x2000_0010, 1).is_ok());
+        assert!(matches!(
+            connection.try_read8(0x2000_0010),
+            Err(Error::UnexpectedResponse(response)) if *response == Message::Ack
+        ));
+        assert!(matches!(connection.recv_message(), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn test_block_at_top_of_address_space() {
+        let connection = Connection::open(EchoTransport::default(), Duration::ZERO).unwrap();
+        let mut buf = [0; 0x81];
+
+        assert!(connection.read_block(0xffff_ff80, &mut buf[..0x80]).is_ok());
+        assert!(matches!(
+            connection.read_block(0xffff_ff80, &mut buf),
+            Err(Error::OutOfRange)
+        ));
+        assert!(connection.write_block(0xffff_ffff, &[1]).is_ok());
+        assert!(matches!(
+            connection.write_block(0xffff_ffff, &[1, 2]),
+            Err(Error::OutOfRange)
+        ));
+        assert!(connection.write_block(0xffff_ffff, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_stale_responses() {
+        let transport = EchoTransport {
+            duplicate: true,
+            ..Default::default()
+        };
+        let connection = Connection::open(transport, TIMEOUT).unwrap();
+
+        // Duplicates of earlier responses are skipped
+        assert_eq!(0x2000_0010, connection.try_read32(0x2000_0010).unwrap());
+        assert_eq!(0x2000_0020, connection.try_read32(0x2000_0020).unwrap());
+
+        // As well as responses to requests given up on
+        let stale = Envelope::new(0x7777, Message::Data(Data::U32(0xdead)));
+        connection.transport().inject(&stale);
+        assert_eq!(0x2000_0030, connection.try_read32(0x2000_0030).unwrap());
+
+        for _ in 0..MAX_STALE_RESPONSES {
+            connection.transport().inject(&stale);
+        }
+        assert!(matches!(
+            connection.try_read32(0x2000_0040),
+            Err(Error::Protocol)
+        ));
+    }
+
+    #[test]
+    fn test_broken_frames() {
+        let connection = Connection::open(EchoTransport::default(), TIMEOUT).unwrap();
+
+        // Rest of a frame cut by an earlier timeout
+        let stale = Envelope::new(0x7777, Message::Block(Block::from_slice(&[0; 32]).unwrap()));
+        connection
+            .transport()
+            .injected
+            .lock()
+            .unwrap()
+            .extend(packets(&stale).into_iter().skip(1));
+        assert_eq!(0x2000_0010, connection.try_read32(0x2000_0010).unwrap());
+
+        // Orphaned last packet
+        connection
+            .transport()
+            .injected
+            .lock()
+            .unwrap()
+            .push(vec![FRAME_END, 0x55]);
+        assert_eq!(0x2000_0020, connection.try_read32(0x2000_0020).unwrap());
+    }
+
+    #[test]
+    fn test_untagged_error() {
+        let connection = Connection::open(EchoTransport::default(), TIMEOUT).unwrap();
+
+        assert!(matches!(
+            connection.request(Message::Nop),
+            Err(Error::Target(ErrorCode::Decode))
+        ));
+        assert_eq!(0x2000_0010, connection.try_read32(0x2000_0010).unwrap());
+    }
+
+    #[test]
+    fn test_packets_are_read_one_by_one() {
+        let connection = Connection::open(EchoTransport::default(), TIMEOUT).unwrap();
+
+        // Response spans several full packets
+        let mut buf = [0; 32];
+        connection.read_block(0x2000_0000, &mut buf).unwrap();
+        assert!(buf.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+        let events = connection.subscribe().unwrap();
+        let custom = Event::Custom {
+            id: 1,
+            payload: Payload::from_slice(&[0x5a; 20]).unwrap(),
+        };
+        let gpio = Event::Gpio {
+            line: 3,
+            rising: true,
+        };
+        let mut queue = connection.transport().events.lock().unwrap();
+        queue.extend(packets(&custom));
+        queue.extend(packets(&gpio));
+        drop(queue);
+        assert_eq!(custom, events.recv_timeout(TIMEOUT).unwrap());
+        assert_eq!(gpio, events.recv_timeout(TIMEOUT).unwrap());
+    }
+}
@@ -1,6 +1,6 @@
-use crate::host::Connection;
+use crate::host::{Connection, DeviceFilter, Error, UsbTransport};
 
-use rusb::{Context, UsbContext as _};
+use rusb::{Context, Direction, Recipient, RequestType, UsbContext as _};
 
 use std::{
     fmt::{self, Debug},
@@ -9,55 +9,91 @@ use std::{
     vec::IntoIter,
 };
 
-use crate::usb::{MESSAGE_MAX_SIZE, PID, USB_IO_IN_ENDPOINT, VID};
+use crate::{
+    config::UsbIoConfig,
+    usb::{PACKET_MAX_SIZE, REQUEST_REBOOT, USB_IO_IN_ENDPOINT, USB_IO_OUT_ENDPOINT},
+};
 
 pub struct Devices(Vec<Device>);
 
 impl Devices {
-    pub fn detect(timeout: Duration) -> Result<Self, rusb::Error> {
+    /// Detect devices with the identity of the reference board
+    pub fn detect(timeout: Duration) -> Result<Self, Error> {
+        Self::detect_with(&[UsbIoConfig::new()], timeout)
+    }
+
+    /// Detect devices matching vendor and product ids of any of `configs`
+    pub fn detect_with(configs: &[UsbIoConfig], timeout: Duration) -> Result<Self, Error> {
+        let filter = configs
+            .iter()
+            .fold(DeviceFilter::new(), |filter, config| filter.config(config));
+        Self::find(&filter, timeout)
+    }
+
+    /// Detect devices selected by `filter`, only devices with matching ids
+    /// and location are opened to read their strings. Devices whose strings
+    /// cannot be read are skipped.
+    pub fn find(filter: &DeviceFilter, timeout: Duration) -> Result<Self, Error> {
         let device_list = Context::new()?.devices()?;
         let mut devices = vec![];
-        println!("USB: enumerating devices...");
+        log!(debug, "enumerating USB devices");
 
         for device in device_list.iter() {
             let desc = device.device_descriptor()?;
 
-            if desc.vendor_id() != VID || desc.product_id() != PID {
+            let (vid, pid) = (desc.vendor_id(), desc.product_id());
+            let ports = device.port_numbers().unwrap_or_default();
+            if !filter.matches_location(vid, pid, device.bus_number(), &ports) {
                 continue;
             }
 
-            println!("found USB-IO device: {:?}", device);
-
-            let mut handle = device.open()?;
-
-            handle.reset()?;
-
-            let language = *handle.read_languages(timeout)?.first().unwrap();
-
-            let t = timeout;
-            let manufacturer = handle.read_manufacturer_string(language, &desc, t)?;
-            let product = handle.read_product_string(language, &desc, t)?;
-            let product_name = format!("{} {}", manufacturer, product);
-            let serial_number = handle.read_serial_number_string(language, &desc, t)?;
-
-            println!(
-                "USB(bus={},addr={}): found {} (serial #{})",
-                device.bus_number(),
-                device.address(),
-                product_name,
-                serial_number,
+            log!(
+                debug,
+                bus = device.bus_number(),
+                address = device.address(),
+                vid,
+                pid,
+                "found USB-IO device"
             );
 
-            devices.push(Device::new(device, product_name, serial_number));
+            let device = match Device::probe(device.clone(), timeout) {
+                Ok(device) => device,
+                // Board of another firmware, held by another process or not
+                // set up yet, the others can still be found
+                #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                Err(err) => {
+                    log!(
+                        warn,
+                        bus = device.bus_number(),
+                        address = device.address(),
+                        error = %err,
+                        "failed to probe USB-IO device"
+                    );
+                    continue;
+                }
+            };
+            if filter.matches_strings(&device.product, &device.serial_number) {
+                devices.push(device);
+            }
         }
 
         if devices.is_empty() {
-            println!("no USB-IO devices found");
+            log!(debug, "no USB-IO devices found");
         }
 
         Ok(Self(devices))
     }
 
+    /// Open the device of the reference board with `serial_number`
+    pub fn open_by_serial(serial_number: &str, timeout: Duration) -> Result<Connection, Error> {
+        let filter = DeviceFilter::new().serial_number(serial_number);
+        Self::find(&filter, timeout)?
+            .into_iter()
+            .next()
+            .ok_or(Error::DeviceNotFound)?
+            .open(timeout)
+    }
+
     /// Number of detected devices
     pub fn len(&self) -> usize {
         self.0.len()
@@ -95,6 +131,9 @@ pub struct Device {
     /// Product vendor and name
     pub product_name: String,
 
+    /// Product name as reported by the device
+    pub(super) product: String,
+
     /// Serial number
     pub serial_number: String,
 }
@@ -103,31 +142,80 @@ impl Device {
     /// Create a new device
     pub(super) fn new(
         device: rusb::Device<rusb::Context>,
-        product_name: String,
+        manufacturer: &str,
+        product: String,
         serial_number: String,
     ) -> Self {
         Self {
             device,
-            product_name,
+            product_name: format!("{} {}", manufacturer, product),
+            product,
             serial_number,
         }
     }
 
-    /// Open this device, consuming it and creating a `UsbConnection`
-    pub fn open(self, timeout: Duration) -> Result<Connection, rusb::Error> {
-        let connection = Connection::create(self, timeout)?;
+    /// Read identity strings of USB-IO `device`, the device is not reset as
+    /// it may be in use by another process
+    pub(super) fn probe(
+        device: rusb::Device<rusb::Context>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let desc = device.device_descriptor()?;
+        let handle = device.open()?;
+
+        let language = *handle
+            .read_languages(timeout)?
+            .first()
+            .ok_or(Error::Usb(rusb::Error::NotFound))?;
+
+        let t = timeout;
+        let manufacturer = handle.read_manufacturer_string(language, &desc, t)?;
+        let product = handle.read_product_string(language, &desc, t)?;
+        let serial_number = handle.read_serial_number_string(language, &desc, t)?;
+        let device = Self::new(device, &manufacturer, product, serial_number);
+
+        log!(
+            debug,
+            bus = device.bus_number(),
+            address = device.address(),
+            product = %device.product_name,
+            serial = %device.serial_number,
+            "probed USB-IO device"
+        );
+
+        Ok(device)
+    }
 
-        println!(
-            "USB(bus={},addr={}): successfully opened {} (serial #{})",
-            connection.device().bus_number(),
-            connection.device().address(),
-            connection.device().product_name,
-            connection.device().serial_number,
+    /// Open this device, consuming it and creating a `Connection`
+    pub fn open(self, timeout: Duration) -> Result<Connection, Error> {
+        let connection = Connection::open(UsbTransport::open(self)?, timeout)?;
+
+        log!(
+            info,
+            bus = connection.device().bus_number(),
+            address = connection.device().address(),
+            product = %connection.device().product_name,
+            serial = %connection.device().serial_number,
+            "opened USB-IO device"
         );
 
         Ok(connection)
     }
 
+    /// Ask the firmware to reboot, the device disconnects and enumerates again
+    pub fn reboot(&self, timeout: Duration) -> Result<(), Error> {
+        let handle = self.device.open()?;
+        // Requests addressed to an unclaimed interface are refused
+        handle.claim_interface(0)?;
+        let request_type =
+            rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface);
+        let result = handle.write_control(request_type, REQUEST_REBOOT, 0, 0, &[], timeout);
+        // The device may be gone already
+        handle.release_interface(0).ok();
+        result?;
+        Ok(())
+    }
+
     /// Get the bus number for this device
     pub fn bus_number(&self) -> u8 {
         self.device.bus_number()
@@ -138,7 +226,34 @@ impl Device {
         self.device.address()
     }
 
-    /// Open a handle to the underlying device (for use by `UsbConnection`)
+    /// Ports from the root hub down to this device, stays the same while
+    /// the device is plugged into the same physical port
+    pub fn port_numbers(&self) -> Vec<u8> {
+        self.device.port_numbers().unwrap_or_default()
+    }
+
+    /// Physical location in Linux sysfs notation, e.g. `1-2.3` for port 3
+    /// of the hub on port 2 of bus 1
+    pub fn port_path(&self) -> String {
+        let ports = self.port_numbers();
+        let ports = ports.iter().map(u8::to_string).collect::<Vec<_>>();
+        format!("{}-{}", self.bus_number(), ports.join("."))
+    }
+
+    /// Packet size of the USB-IO bulk endpoints, taken from the endpoint descriptor
+    pub fn max_packet_size(&self) -> Result<usize, Error> {
+        let config = self.device.active_config_descriptor()?;
+
+        config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .flat_map(|descriptor| descriptor.endpoint_descriptors().collect::<Vec<_>>())
+            .find(|endpoint| endpoint.address() == USB_IO_OUT_ENDPOINT)
+            .map(|endpoint| endpoint.max_packet_size() as usize)
+            .ok_or(Error::Usb(rusb::Error::NotFound))
+    }
+
+    /// Open a handle to the underlying device (for use by `UsbTransport`)
     pub(super) fn open_handle(&self) -> Result<rusb::DeviceHandle<rusb::Context>, rusb::Error> {
         let mut handle = self.device.open()?;
         handle.reset()?;
@@ -153,7 +268,7 @@ impl Device {
     /// Flush any unconsumed messages still in the buffer to get the connection
     /// back into a clean state
     fn flush(handle: &mut rusb::DeviceHandle<rusb::Context>) -> Result<(), rusb::Error> {
-        let mut buffer = [0u8; MESSAGE_MAX_SIZE as usize];
+        let mut buffer = [0u8; PACKET_MAX_SIZE];
 
         // Use a near instantaneous (but non-zero) timeout to drain the buffer.
         // Zero is interpreted as wait forever.
@@ -161,7 +276,7 @@ impl Device {
 
         match handle.read_bulk(USB_IO_IN_ENDPOINT, &mut buffer, timeout) {
             Ok(_) | Err(rusb::Error::Timeout) => Ok(()),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 }
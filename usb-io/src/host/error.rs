@@ -0,0 +1,79 @@
+use std::fmt;
+
+use crate::message::{ErrorCode, Message};
+
+/// Errors of the host side of USB-IO
+#[derive(Debug)]
+pub enum Error {
+    /// USB transport failure
+    Usb(rusb::Error),
+    /// Transfer did not complete in time
+    Timeout,
+    /// Request is not supported by the transport or was rejected by the target
+    Unsupported,
+    /// Packet does not fit into the link or the receive buffer
+    Overflow,
+    /// Message could not be serialized
+    Encode(postcard::Error),
+    /// Received data could not be deserialized
+    Decode(postcard::Error),
+    /// Response kind does not match the request
+    UnexpectedResponse(Box<Message>),
+    /// Target reported an error
+    Target(ErrorCode),
+    /// Operation of a batch at `index` failed, later operations were not executed
+    Batch { index: usize, code: ErrorCode },
+    /// Block of target memory wraps around the end of the address space
+    OutOfRange,
+    /// No connected device matches
+    DeviceNotFound,
+    /// Connection is closed, e.g. the I/O thread of an `AsyncConnection` panicked
+    Disconnected,
+    /// Packets do not form a frame or responses do not follow the protocol
+    Protocol,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usb(err) => write!(f, "USB error: {}", err),
+            Error::Timeout => f.write_str("transfer timed out"),
+            Error::Unsupported => f.write_str("request not supported"),
+            Error::Overflow => f.write_str("packet too large"),
+            Error::Encode(_) => f.write_str("failed to encode message"),
+            Error::Decode(_) => f.write_str("failed to decode message"),
+            Error::UnexpectedResponse(message) => {
+                write!(f, "unexpected {} response", message.kind())
+            }
+            Error::Target(code) => write!(f, "target error: {}", code),
+            Error::Batch { index, code } => {
+                write!(f, "batch operation {} failed: {}", index, code)
+            }
+            Error::OutOfRange => f.write_str("address range wraps around"),
+            Error::DeviceNotFound => f.write_str("no USB-IO device found"),
+            Error::Disconnected => f.write_str("connection is closed"),
+            Error::Protocol => f.write_str("protocol violation"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Usb(err) => Some(err),
+            Error::Encode(err) | Error::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusb::Error> for Error {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Timeout => Error::Timeout,
+            rusb::Error::NotSupported => Error::Unsupported,
+            rusb::Error::Overflow => Error::Overflow,
+            err => Error::Usb(err),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use postcard::from_bytes;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    frame::Assembler,
+    host::{Error, Transport, STOP_POLL_TIMEOUT},
+    message::Event,
+    usb::{FRAME_MAX_SIZE, PACKET_MAX_SIZE},
+};
+
+type Subscribers = Arc<Mutex<Vec<Sender<Event>>>>;
+
+/// Thread reading events of the target and fanning them out to subscribers
+pub(crate) struct EventPump {
+    subscribers: Subscribers,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventPump {
+    /// Start reading events in packets of `packet_size` bytes
+    pub fn start<T: Transport + 'static>(transport: Arc<T>, packet_size: usize) -> Self {
+        let subscribers = Subscribers::default();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let subscribers = subscribers.clone();
+            let stop = stop.clone();
+            thread::spawn(move || pump(&*transport, packet_size, &subscribers, &stop))
+        };
+
+        Self {
+            subscribers,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// The thread stopped on a transport error, its subscribers are disconnected
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl Drop for EventPump {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn pump<T: Transport>(
+    transport: &T,
+    packet_size: usize,
+    subscribers: &Mutex<Vec<Sender<Event>>>,
+    stop: &AtomicBool,
+) {
+    let mut assembler = Assembler::<FRAME_MAX_SIZE>::new();
+    let mut buf = [0; PACKET_MAX_SIZE];
+    // Single packet, see `Transport::recv_event_packet`
+    let buf = &mut buf[..packet_size];
+
+    while !stop.load(Ordering::Relaxed) {
+        let size = match transport.recv_event_packet(buf, STOP_POLL_TIMEOUT) {
+            Ok(size) => size,
+            Err(Error::Timeout) => continue,
+            Err(_) => break,
+        };
+
+        // Malformed frames are dropped, the assembler resyncs on the next start
+        let event = match assembler.push(&buf[..size]) {
+            Ok(Some(frame)) => match from_bytes::<Event>(frame) {
+                Ok(event) => event,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+
+        // Receivers which were dropped unsubscribe
+        subscribers
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    // Disconnect subscribers so that they notice the pump stopped
+    subscribers.lock().unwrap().clear();
+}
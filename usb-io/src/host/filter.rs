@@ -0,0 +1,129 @@
+use crate::config::UsbIoConfig;
+
+/// Selects USB-IO devices to detect.
+///
+/// Ids and the port path are checked before a device is opened, strings
+/// are read only from devices passing these checks.
+///
+/// ```
+/// use usb_io::host::DeviceFilter;
+///
+/// // Board plugged into port 3 of the hub on port 1 of bus 2
+/// let station = DeviceFilter::new().port_path(2, &[1, 3]);
+/// let board = DeviceFilter::new().serial_prefix("3A00");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    ids: Vec<(u16, u16)>,
+    serial_number: Option<String>,
+    serial_prefix: Option<String>,
+    product: Option<String>,
+    bus_number: Option<u8>,
+    ports: Option<Vec<u8>>,
+}
+
+impl DeviceFilter {
+    /// Filter matching any device with the identity of the reference board
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match vendor and product ids of `config`, may be given several times
+    pub fn config(self, config: &UsbIoConfig) -> Self {
+        self.vid_pid(config.vid, config.pid)
+    }
+
+    /// Match vendor and product ids, may be given several times
+    pub fn vid_pid(mut self, vid: u16, pid: u16) -> Self {
+        self.ids.push((vid, pid));
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_owned());
+        self
+    }
+
+    pub fn serial_prefix(mut self, prefix: &str) -> Self {
+        self.serial_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Match product string, without the manufacturer
+    pub fn product(mut self, product: &str) -> Self {
+        self.product = Some(product.to_owned());
+        self
+    }
+
+    pub fn bus_number(mut self, bus_number: u8) -> Self {
+        self.bus_number = Some(bus_number);
+        self
+    }
+
+    /// Match physical location: `bus_number` and the ports from the root
+    /// hub down to the device, `1-2.3` in Linux sysfs notation is `(1, &[2, 3])`
+    pub fn port_path(mut self, bus_number: u8, ports: &[u8]) -> Self {
+        self.bus_number = Some(bus_number);
+        self.ports = Some(ports.to_vec());
+        self
+    }
+
+    /// Device with ids and location matches, strings are still to be checked
+    pub(super) fn matches_location(
+        &self,
+        vid: u16,
+        pid: u16,
+        bus_number: u8,
+        ports: &[u8],
+    ) -> bool {
+        let ids = match self.ids.is_empty() {
+            true => UsbIoConfig::new().matches(vid, pid),
+            false => self.ids.contains(&(vid, pid)),
+        };
+
+        ids && self.bus_number.is_none_or(|bus| bus == bus_number)
+            && self.ports.as_ref().is_none_or(|path| path == ports)
+    }
+
+    pub(super) fn matches_strings(&self, product: &str, serial_number: &str) -> bool {
+        self.serial_number
+            .as_ref()
+            .is_none_or(|serial| serial == serial_number)
+            && self
+                .serial_prefix
+                .as_ref()
+                .is_none_or(|prefix| serial_number.starts_with(prefix.as_str()))
+            && self.product.as_ref().is_none_or(|name| name == product)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::usb::{PID, VID};
+
+    #[test]
+    fn test_device_filter() {
+        let any = DeviceFilter::new();
+        assert!(any.matches_location(VID, PID, 1, &[2]));
+        assert!(!any.matches_location(VID, PID + 1, 1, &[2]));
+
+        let variants = DeviceFilter::new()
+            .config(&UsbIoConfig::new())
+            .vid_pid(0x1209, 0x0001);
+        assert!(variants.matches_location(0x1209, 0x0001, 1, &[2]));
+        assert!(variants.matches_location(VID, PID, 1, &[2]));
+
+        let station = DeviceFilter::new().port_path(1, &[2, 3]);
+        assert!(station.matches_location(VID, PID, 1, &[2, 3]));
+        assert!(!station.matches_location(VID, PID, 1, &[2]));
+        assert!(!station.matches_location(VID, PID, 2, &[2, 3]));
+
+        let board = DeviceFilter::new()
+            .serial_prefix("3A00")
+            .product("USB-IO USB class");
+        assert!(board.matches_strings("USB-IO USB class", "3A0012"));
+        assert!(!board.matches_strings("USB-IO USB class", "3B0012"));
+        assert!(!board.matches_strings("USB-IO mini", "3A0012"));
+    }
+}
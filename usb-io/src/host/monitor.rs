@@ -0,0 +1,245 @@
+use rusb::{Context, Hotplug, HotplugBuilder, UsbContext as _};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    config::UsbIoConfig,
+    host::{Device, Error, STOP_POLL_TIMEOUT},
+};
+
+/// Change of the set of connected USB-IO devices
+#[derive(Debug)]
+pub enum DeviceEvent<D = Device> {
+    /// Device was plugged in or finished rebooting
+    Arrived(D),
+    /// Device was unplugged, the serial number is known for devices which
+    /// arrived while the monitor was running
+    Left {
+        bus_number: u8,
+        address: u8,
+        serial_number: Option<String>,
+    },
+}
+
+/// Hotplug notification recorded in the libusb callback, handled once
+/// synchronous requests are allowed again. Devices are located by bus
+/// number and address.
+enum Change<D> {
+    Arrived(u8, u8, D),
+    Left(u8, u8),
+}
+
+type Changes = Mutex<Vec<Change<rusb::Device<Context>>>>;
+
+struct Recorder(Arc<Changes>);
+
+impl Hotplug<Context> for Recorder {
+    fn device_arrived(&mut self, device: rusb::Device<Context>) {
+        let (bus_number, address) = (device.bus_number(), device.address());
+        let change = Change::Arrived(bus_number, address, device);
+        self.0.lock().unwrap().push(change);
+    }
+
+    fn device_left(&mut self, device: rusb::Device<Context>) {
+        let change = Change::Left(device.bus_number(), device.address());
+        self.0.lock().unwrap().push(change);
+    }
+}
+
+/// Arrived device whose serial number is reported again when it leaves
+trait Identified {
+    fn serial_number(&self) -> &str;
+}
+
+impl Identified for Device {
+    fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+}
+
+/// Watches arrival and removal of USB-IO devices with libusb hotplug
+/// callbacks, devices connected before the start are reported as arrived
+pub struct DeviceMonitor {
+    events: Receiver<DeviceEvent>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceMonitor {
+    /// Monitor devices with the identity of the reference board
+    pub fn start(timeout: Duration) -> Result<Self, Error> {
+        Self::start_with(&[UsbIoConfig::new()], timeout)
+    }
+
+    /// Monitor devices matching vendor and product ids of any of `configs`,
+    /// `timeout` applies to reading identity strings of arrived devices
+    pub fn start_with(configs: &[UsbIoConfig], timeout: Duration) -> Result<Self, Error> {
+        if !rusb::has_hotplug() {
+            return Err(Error::Unsupported);
+        }
+
+        let context = Context::new()?;
+        let changes = Arc::new(Mutex::new(vec![]));
+        let registrations = configs
+            .iter()
+            .map(|config| {
+                HotplugBuilder::new()
+                    .vendor_id(config.vid)
+                    .product_id(config.pid)
+                    .enumerate(true)
+                    .register(&context, Box::new(Recorder(changes.clone())))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (sender, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _registrations = registrations;
+                run(&context, &changes, &sender, &stop, timeout)
+            })
+        };
+
+        Ok(Self {
+            events,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Receiver of device events, disconnected if libusb event handling fails
+    pub fn events(&self) -> &Receiver<DeviceEvent> {
+        &self.events
+    }
+}
+
+impl Drop for DeviceMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(
+    context: &Context,
+    changes: &Changes,
+    sender: &Sender<DeviceEvent>,
+    stop: &AtomicBool,
+    timeout: Duration,
+) {
+    let mut serials = BTreeMap::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        if context.handle_events(Some(STOP_POLL_TIMEOUT)).is_err() {
+            return;
+        }
+
+        let changes = std::mem::take(&mut *changes.lock().unwrap());
+        let probe = |device| Device::probe(device, timeout);
+        for event in handle_changes(changes, &mut serials, probe) {
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Map hotplug `changes` to events, arrived devices are identified with
+/// `probe` and skipped if that fails. `serials` keeps serial numbers of
+/// arrived devices by bus number and address.
+fn handle_changes<D, A: Identified>(
+    changes: Vec<Change<D>>,
+    serials: &mut BTreeMap<(u8, u8), String>,
+    mut probe: impl FnMut(D) -> Result<A, Error>,
+) -> Vec<DeviceEvent<A>> {
+    let mut events = vec![];
+    for change in changes {
+        match change {
+            Change::Arrived(bus_number, address, device) => match probe(device) {
+                Ok(device) => {
+                    let serial_number = device.serial_number().to_owned();
+                    serials.insert((bus_number, address), serial_number);
+                    events.push(DeviceEvent::Arrived(device));
+                }
+                // Device left again or is not ready, nothing to report
+                #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                Err(err) => log!(
+                    warn,
+                    bus = bus_number,
+                    address,
+                    error = %err,
+                    "failed to probe arrived USB-IO device"
+                ),
+            },
+            Change::Left(bus_number, address) => events.push(DeviceEvent::Left {
+                bus_number,
+                address,
+                serial_number: serials.remove(&(bus_number, address)),
+            }),
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    impl Identified for String {
+        fn serial_number(&self) -> &str {
+            self
+        }
+    }
+
+    /// Devices with an empty serial number fail to probe
+    fn probe(serial_number: &str) -> Result<String, Error> {
+        match serial_number {
+            "" => Err(Error::Usb(rusb::Error::Busy)),
+            serial_number => Ok(serial_number.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_device_events() {
+        let mut serials = BTreeMap::new();
+
+        let changes = vec![
+            Change::Arrived(1, 5, "A"),
+            Change::Arrived(1, 6, ""),
+            Change::Arrived(2, 5, "B"),
+        ];
+        let events = handle_changes(changes, &mut serials, probe);
+        assert!(matches!(
+            &events[..],
+            [DeviceEvent::Arrived(a), DeviceEvent::Arrived(b)] if a == "A" && b == "B"
+        ));
+
+        // Serial numbers are known for devices which arrived before
+        let changes = vec![Change::Left(1, 5), Change::Left(1, 6), Change::Left(1, 5)];
+        let events = handle_changes(changes, &mut serials, probe);
+        let serials_left = events
+            .iter()
+            .map(|event| match event {
+                DeviceEvent::Left {
+                    bus_number: 1,
+                    serial_number,
+                    ..
+                } => serial_number.as_deref(),
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some("A"), None, None], serials_left);
+        assert_eq!(Some("B"), serials.get(&(2, 5)).map(String::as_str));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::UsbIoConfig,
+    host::{Connection, DeviceFilter, Devices, Error, Transport, UsbTransport},
+    memory_interface::MemoryInterface,
+};
+
+/// Time to open the device again, it may still be enumerating
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// First interval between attempts to open the device again, doubled after
+/// every failed attempt
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest interval between attempts to open the device again
+const RECONNECT_INTERVAL_MAX: Duration = Duration::from_millis(400);
+
+type Open<T> = Box<dyn Fn() -> Result<Connection<T>, Error> + Send + Sync>;
+
+type Init<T> = Box<dyn Fn(&Connection<T>) -> Result<(), Error> + Send + Sync>;
+
+/// `Connection` to the device with a given serial number, which is opened
+/// again when the device was re-plugged or rebooted.
+///
+/// The init sequence runs after every (re)open, to restore the state the
+/// firmware lost, e.g. clocks and pin configuration.
+pub struct ReconnectingConnection<T: Transport = UsbTransport> {
+    serial_number: String,
+    open: Open<T>,
+    init: Init<T>,
+    connection: Mutex<Option<Arc<Connection<T>>>>,
+}
+
+impl ReconnectingConnection {
+    /// Open device with `serial_number` and run `init` on it
+    pub fn open(
+        serial_number: &str,
+        timeout: Duration,
+        init: impl Fn(&Connection) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        Self::open_with(&[UsbIoConfig::new()], serial_number, timeout, init)
+    }
+
+    /// Open device with `serial_number` matching any of `configs` and run `init` on it
+    pub fn open_with(
+        configs: &[UsbIoConfig],
+        serial_number: &str,
+        timeout: Duration,
+        init: impl Fn(&Connection) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        let filter = configs
+            .iter()
+            .fold(DeviceFilter::new(), |filter, config| filter.config(config))
+            .serial_number(serial_number);
+        let open = move || {
+            Devices::find(&filter, timeout)?
+                .into_iter()
+                .next()
+                .ok_or(Error::DeviceNotFound)?
+                .open(timeout)
+        };
+        Self::new(serial_number, open, init)
+    }
+}
+
+impl<T: Transport> ReconnectingConnection<T> {
+    /// Open connection to the device with `serial_number` with `open` and
+    /// run `init` on it, `open` is called again whenever the device was lost
+    pub fn new(
+        serial_number: &str,
+        open: impl Fn() -> Result<Connection<T>, Error> + Send + Sync + 'static,
+        init: impl Fn(&Connection<T>) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        let reconnecting = Self {
+            serial_number: serial_number.to_owned(),
+            open: Box::new(open),
+            init: Box::new(init),
+            connection: Mutex::new(None),
+        };
+        reconnecting.connection()?;
+        Ok(reconnecting)
+    }
+
+    pub fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+
+    /// Current connection, the device is opened again if it was lost
+    pub fn connection(&self) -> Result<Arc<Connection<T>>, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        let reopened = Arc::new(self.reopen()?);
+        *connection = Some(reopened.clone());
+        Ok(reopened)
+    }
+
+    /// Run `f` on the connection, if the device was lost it is opened again
+    /// and `f` is retried once
+    pub fn with<R>(&self, f: impl Fn(&Connection<T>) -> Result<R, Error>) -> Result<R, Error> {
+        let connection = self.connection()?;
+        match f(&connection) {
+            Err(err) if is_disconnect(&err) => {
+                self.drop_connection(&connection);
+                f(&*self.connection()?)
+            }
+            result => result,
+        }
+    }
+
+    /// Forget `lost` unless another thread reconnected already
+    fn drop_connection(&self, lost: &Arc<Connection<T>>) {
+        let mut connection = self.connection.lock().unwrap();
+        if connection
+            .as_ref()
+            .is_some_and(|connection| Arc::ptr_eq(connection, lost))
+        {
+            *connection = None;
+        }
+    }
+
+    /// Open the device, retrying with growing intervals while it is not
+    /// ready yet, until `RECONNECT_TIMEOUT` passed
+    fn reopen(&self) -> Result<Connection<T>, Error> {
+        let deadline = Instant::now() + RECONNECT_TIMEOUT;
+        let mut interval = RECONNECT_INTERVAL;
+
+        loop {
+            match (self.open)() {
+                Ok(connection) => {
+                    (self.init)(&connection)?;
+                    return Ok(connection);
+                }
+                Err(err) if is_not_ready(&err) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(err);
+                    }
+                    thread::sleep(interval.min(remaining));
+                    interval = (interval * 2).min(RECONNECT_INTERVAL_MAX);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Error means the device is gone, rather than a failed request.
+///
+/// Stalls and transient I/O errors of a live device are not, retrying the
+/// request on a new connection would run writes twice.
+fn is_disconnect(err: &Error) -> bool {
+    matches!(err, Error::Usb(rusb::Error::NoDevice))
+}
+
+/// Error of opening a device which is still enumerating, or held by the
+/// system driver or udev for a moment after it appeared
+fn is_not_ready(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::DeviceNotFound
+            | Error::Usb(
+                rusb::Error::NoDevice
+                    | rusb::Error::NotFound
+                    | rusb::Error::Io
+                    | rusb::Error::Pipe
+                    | rusb::Error::Access
+                    | rusb::Error::Busy
+            )
+    )
+}
+
+impl<T: Transport> MemoryInterface for ReconnectingConnection<T> {
+    type Error = Error;
+
+    fn try_read8(&self, address: u32) -> Result<u8, Self::Error> {
+        self.with(|connection| connection.try_read8(address))
+    }
+
+    fn try_read16(&self, address: u32) -> Result<u16, Self::Error> {
+        self.with(|connection| connection.try_read16(address))
+    }
+
+    fn try_read32(&self, address: u32) -> Result<u32, Self::Error> {
+        self.with(|connection| connection.try_read32(address))
+    }
+
+    fn try_write8(&self, address: u32, value: u8) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_write8(address, value))
+    }
+
+    fn try_write16(&self, address: u32, value: u16) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_write16(address, value))
+    }
+
+    fn try_write32(&self, address: u32, value: u32) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_write32(address, value))
+    }
+
+    fn try_modify32(&self, address: u32, mask: u32, value: u32) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_modify32(address, mask, value))
+    }
+
+    fn try_set_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_set_bits32(address, bits))
+    }
+
+    fn try_clear_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        self.with(|connection| connection.try_clear_bits32(address, bits))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::host::{
+        sim::{SimMemory, SimTarget},
+        TIMEOUT,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const RAM: u32 = 0x2000_0000;
+
+    /// Simulated target whose transfers fail with `failure` while it is set
+    struct Pluggable {
+        target: SimTarget,
+        failure: Arc<Mutex<Option<rusb::Error>>>,
+    }
+
+    impl Pluggable {
+        fn check(&self) -> Result<(), Error> {
+            match *self.failure.lock().unwrap() {
+                Some(err) => Err(Error::Usb(err)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Transport for Pluggable {
+        fn max_packet_size(&self) -> usize {
+            self.target.max_packet_size()
+        }
+
+        fn send_packet(&self, packet: &[u8], timeout: Duration) -> Result<(), Error> {
+            self.check()?;
+            self.target.send_packet(packet, timeout)
+        }
+
+        fn recv_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+            self.check()?;
+            self.target.recv_packet(buf, timeout)
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let failure = Arc::new(Mutex::new(None));
+
+        let open = {
+            let (opens, failure) = (opens.clone(), failure.clone());
+            move || {
+                // Device is still enumerating on the first attempt
+                if opens.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(Error::Usb(rusb::Error::NoDevice));
+                }
+                *failure.lock().unwrap() = None;
+                let target = SimTarget::new(SimMemory::new().ram(RAM, 0x100));
+                let transport = Pluggable {
+                    target,
+                    failure: failure.clone(),
+                };
+                Connection::open(transport, TIMEOUT)
+            }
+        };
+        let init = |connection: &Connection<Pluggable>| connection.try_write32(RAM, 0x1234);
+        let reconnecting = ReconnectingConnection::new("sim", open, init).unwrap();
+        assert_eq!(2, opens.load(Ordering::SeqCst));
+        assert_eq!(0x1234, reconnecting.try_read32(RAM).unwrap());
+
+        // Stalls of a live device fail the request, it is not run again
+        *failure.lock().unwrap() = Some(rusb::Error::Pipe);
+        assert!(matches!(
+            reconnecting.try_set_bits32(RAM, 1),
+            Err(Error::Usb(rusb::Error::Pipe))
+        ));
+        assert_eq!(2, opens.load(Ordering::SeqCst));
+        *failure.lock().unwrap() = None;
+
+        // Device is re-plugged, the request is retried on a new connection
+        // with the state restored by init
+        *failure.lock().unwrap() = Some(rusb::Error::NoDevice);
+        reconnecting.try_write32(RAM + 4, 0x5678).unwrap();
+        assert_eq!(3, opens.load(Ordering::SeqCst));
+        assert_eq!(0x1234, reconnecting.try_read32(RAM).unwrap());
+        assert_eq!(0x5678, reconnecting.try_read32(RAM + 4).unwrap());
+
+        // Errors other than the device not being ready are not retried
+        let open = || -> Result<Connection<SimTarget>, Error> { Err(Error::Protocol) };
+        let result = ReconnectingConnection::new("sim", open, |_| Ok(()));
+        assert!(matches!(result, Err(Error::Protocol)));
+    }
+}
@@ -0,0 +1,715 @@
+//! In-process simulated USB-IO target for testing host code without a board.
+//!
+//! [`SimTarget`] runs the target side of the protocol against a sparse
+//! emulated memory map and is used as the `Transport` of a `Connection`:
+//!
+//! ```
+//! use usb_io::host::{sim::{SimMemory, SimTarget}, Connection, TIMEOUT};
+//! use usb_io::MemoryInterface;
+//!
+//! let target = SimTarget::new(SimMemory::new().ram(0x2000_0000, 0x100));
+//! let connection = Connection::open(target, TIMEOUT).unwrap();
+//! connection.try_write32(0x2000_0000, 42).unwrap();
+//! assert_eq!(42, connection.try_read32(0x2000_0000).unwrap());
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    handler::{Command, TargetHandler},
+    host::{Error, Transport},
+    message::{Capabilities, Data, DataSize, ErrorCode, Event, Interrupt},
+    policy::AccessPolicy,
+    target::Target,
+    usb::DEFAULT_PACKET_SIZE,
+};
+
+/// Interval of checking the condition of a pending `WaitFor` request
+const WAIT_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+/// Interval of checking the event queue while the host waits for events
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+type ReadHook = Box<dyn FnMut(u32) -> u32 + Send>;
+type WriteHook = Box<dyn FnMut(u32, u32) -> u32 + Send>;
+
+/// Emulated 32 bit register with optional side effects
+pub struct Register {
+    value: u32,
+    on_read: Option<ReadHook>,
+    on_write: Option<WriteHook>,
+}
+
+impl Register {
+    pub fn new(reset_value: u32) -> Self {
+        Self {
+            value: reset_value,
+            on_read: None,
+            on_write: None,
+        }
+    }
+
+    /// Compute value returned by reads from the stored value, the stored value is kept
+    pub fn on_read(mut self, hook: impl FnMut(u32) -> u32 + Send + 'static) -> Self {
+        self.on_read = Some(Box::new(hook));
+        self
+    }
+
+    /// Compute value stored by writes from the stored and the written value,
+    /// writes narrower than a word keep the other bytes of the stored value
+    pub fn on_write(mut self, hook: impl FnMut(u32, u32) -> u32 + Send + 'static) -> Self {
+        self.on_write = Some(Box::new(hook));
+        self
+    }
+
+    fn read(&mut self) -> u32 {
+        match self.on_read.as_mut() {
+            Some(hook) => hook(self.value),
+            None => self.value,
+        }
+    }
+
+    /// Write `value` to the bytes selected by `lanes`, the hook sees the
+    /// written bytes only, so write one to clear bits of other bytes stay set
+    fn write(&mut self, value: u32, lanes: u32) {
+        let written = match self.on_write.as_mut() {
+            Some(hook) => hook(self.value, value & lanes),
+            None => value,
+        };
+        self.value = self.value & !lanes | written & lanes;
+    }
+}
+
+/// Contiguous bytes of RAM or ROM
+struct Area {
+    start: u32,
+    bytes: Vec<u8>,
+    writable: bool,
+}
+
+impl Area {
+    fn range(&self, address: u32, len: u32) -> Option<std::ops::Range<usize>> {
+        let offset = address.checked_sub(self.start)? as usize;
+        let end = offset.checked_add(len as usize)?;
+        (end <= self.bytes.len()).then_some(offset..end)
+    }
+}
+
+/// Sparse memory map, accesses outside of mapped areas and registers are bus faults
+#[derive(Default)]
+pub struct SimMemory {
+    areas: Vec<Area>,
+    registers: BTreeMap<u32, Register>,
+}
+
+impl SimMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map zeroed RAM of `len` bytes
+    pub fn ram(mut self, start: u32, len: u32) -> Self {
+        self.areas.push(Area {
+            start,
+            bytes: vec![0; len as usize],
+            writable: true,
+        });
+        self
+    }
+
+    /// Map read-only `bytes`, writes are bus faults
+    pub fn rom(mut self, start: u32, bytes: &[u8]) -> Self {
+        self.areas.push(Area {
+            start,
+            bytes: bytes.to_vec(),
+            writable: false,
+        });
+        self
+    }
+
+    /// Map register at word aligned `address`
+    pub fn register(mut self, address: u32, register: Register) -> Self {
+        self.registers.insert(address & !3, register);
+        self
+    }
+
+    /// Read stored word without side effects, registers are found by the
+    /// word containing `address`
+    pub fn peek32(&self, address: u32) -> Option<u32> {
+        if let Some(register) = self.registers.get(&(address & !3)) {
+            return Some(register.value);
+        }
+        let area = self
+            .areas
+            .iter()
+            .find(|area| area.range(address, 4).is_some())?;
+        let range = area.range(address, 4)?;
+        Some(u32::from_le_bytes(area.bytes[range].try_into().unwrap()))
+    }
+
+    /// Store word without side effects, ROM included, registers are found by
+    /// the word containing `address`
+    pub fn poke32(&mut self, address: u32, value: u32) -> bool {
+        if let Some(register) = self.registers.get_mut(&(address & !3)) {
+            register.value = value;
+            return true;
+        }
+        for area in self.areas.iter_mut() {
+            if let Some(range) = area.range(address, 4) {
+                area.bytes[range].copy_from_slice(&value.to_le_bytes());
+                return true;
+            }
+        }
+        false
+    }
+
+    fn area_mut(&mut self, address: u32, len: u32, write: bool) -> Result<&mut [u8], ErrorCode> {
+        let area = self
+            .areas
+            .iter_mut()
+            .find(|area| area.range(address, len).is_some())
+            .ok_or(ErrorCode::BusFault)?;
+        if write && !area.writable {
+            return Err(ErrorCode::BusFault);
+        }
+        let range = area.range(address, len).unwrap();
+        Ok(&mut area.bytes[range])
+    }
+}
+
+/// Position of data of `size` at `address` inside of its word
+fn lane(address: u32, size: DataSize) -> (u32, u32) {
+    let shift = (address & 3) * 8;
+    let mask = match size {
+        DataSize::U8 => 0xff,
+        DataSize::U16 => 0xffff,
+        DataSize::U32 => u32::MAX,
+    };
+    (shift, mask)
+}
+
+fn data_from(size: DataSize, value: u32) -> Data {
+    match size {
+        DataSize::U8 => Data::U8(value as u8),
+        DataSize::U16 => Data::U16(value as u16),
+        DataSize::U32 => Data::U32(value),
+    }
+}
+
+fn data_value(data: Data) -> u32 {
+    match data {
+        Data::U8(value) => value as u32,
+        Data::U16(value) => value as u32,
+        Data::U32(value) => value,
+    }
+}
+
+impl TargetHandler for SimMemory {
+    fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+        if let Some(register) = self.registers.get_mut(&(address & !3)) {
+            let (shift, mask) = lane(address, size);
+            return Ok(data_from(size, register.read() >> shift & mask));
+        }
+
+        let bytes = self.area_mut(address, size.bytes(), false)?;
+        let mut word = [0; 4];
+        word[..bytes.len()].copy_from_slice(bytes);
+        Ok(data_from(size, u32::from_le_bytes(word)))
+    }
+
+    fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode> {
+        if let Some(register) = self.registers.get_mut(&(address & !3)) {
+            let (shift, mask) = lane(address, data.size());
+            register.write(data_value(data) << shift, mask << shift);
+            return Ok(());
+        }
+
+        let bytes = self.area_mut(address, data.size().bytes(), true)?;
+        let len = bytes.len();
+        bytes.copy_from_slice(&data_value(data).to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn features(&self) -> u32 {
+        Capabilities::FEATURE_BUS_FAULT
+    }
+
+    /// Interrupts are raised with `SimTarget::raise_interrupt`
+    fn set_interrupt(&mut self, _irq: u8, _enabled: bool) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+}
+
+/// Simulated target connected as a loopback `Transport`
+pub struct SimTarget {
+    target: Mutex<Target<SimMemory>>,
+    /// Origin of interrupt timestamps
+    started: Instant,
+}
+
+impl SimTarget {
+    /// Create target allowing access to the whole memory map
+    pub fn new(memory: SimMemory) -> Self {
+        let target = Target::new(
+            memory,
+            AccessPolicy::allow_all(),
+            DEFAULT_PACKET_SIZE as u16,
+        );
+
+        Self {
+            target: Mutex::new(target),
+            started: Instant::now(),
+        }
+    }
+
+    /// Check host accesses against `policy`
+    pub fn policy(self, policy: AccessPolicy) -> Self {
+        self.target.lock().unwrap().policy = policy;
+        self
+    }
+
+    /// Set size of packets messages are split into
+    pub fn packet_size(self, packet_size: u16) -> Self {
+        self.target.lock().unwrap().packet_size = packet_size;
+        self
+    }
+
+    /// Set firmware build identifier reported to the host
+    pub fn build_id(self, build_id: u32) -> Self {
+        self.target.lock().unwrap().build_id = build_id;
+        self
+    }
+
+    /// Register custom command `id`, answering `Message::Custom` requests
+    pub fn command(self, id: u16, command: Command<SimMemory>) -> Self {
+        self.target.lock().unwrap().register(id, command);
+        self
+    }
+
+    /// Allow host to forward interrupt lines `irqs`
+    pub fn forward_interrupts(self, irqs: &[u8]) -> Self {
+        let mut target = self.target.lock().unwrap();
+        for &irq in irqs {
+            target.interrupts.allow(irq);
+        }
+        drop(target);
+        self
+    }
+
+    /// Raise interrupt line `irq`, returns `false` if the host does not forward it
+    pub fn raise_interrupt(&self, irq: u8) -> bool {
+        let timestamp_us = self.started.elapsed().as_micros() as u32;
+        let mut target = self.target.lock().unwrap();
+        target.interrupts.is_enabled(irq)
+            && target
+                .events
+                .push(Event::Interrupt(Interrupt { irq, timestamp_us }))
+    }
+
+    /// Queue event for the host, returns `false` if the queue is full
+    pub fn push_event(&self, event: Event) -> bool {
+        self.target.lock().unwrap().events.push(event)
+    }
+
+    /// Access memory of the target, e.g. to check results of requests
+    pub fn memory<R>(&self, f: impl FnOnce(&mut SimMemory) -> R) -> R {
+        f(&mut self.target.lock().unwrap().handler)
+    }
+}
+
+impl Transport for SimTarget {
+    fn max_packet_size(&self) -> usize {
+        self.target.lock().unwrap().packet_size as usize
+    }
+
+    fn send_packet(&self, packet: &[u8], _timeout: Duration) -> Result<(), Error> {
+        let mut target = self.target.lock().unwrap();
+        if packet.len() > target.packet_size as usize {
+            return Err(Error::Overflow);
+        }
+        target.receive(packet);
+        Ok(())
+    }
+
+    fn recv_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let started = Instant::now();
+        let mut polled = started;
+
+        loop {
+            let mut target = self.target.lock().unwrap();
+            let packet_size = (target.packet_size as usize).min(buf.len());
+            if let Some(size) = target.next_packet(&mut buf[..packet_size]) {
+                return Ok(size);
+            }
+            if !target.wait_pending() || started.elapsed() >= timeout {
+                return Err(Error::Timeout);
+            }
+
+            let elapsed_us = polled.elapsed().as_micros().min(u32::MAX as u128) as u32;
+            polled = Instant::now();
+            if !target.poll_wait(elapsed_us) {
+                drop(target);
+                thread::sleep(WAIT_POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn control_in(&self, request: u8, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        let target = self.target.lock().unwrap();
+        target.control_in(request, buf).ok_or(Error::Unsupported)
+    }
+
+    fn control_out(&self, request: u8, _timeout: Duration) -> Result<(), Error> {
+        let mut target = self.target.lock().unwrap();
+        if target.control_out(request) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    fn recv_event_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let started = Instant::now();
+
+        loop {
+            let mut target = self.target.lock().unwrap();
+            let packet_size = (target.packet_size as usize).min(buf.len());
+            if let Some(size) = target.events.next_packet(&mut buf[..packet_size]) {
+                return Ok(size);
+            }
+            drop(target);
+
+            if started.elapsed() >= timeout {
+                return Err(Error::Timeout);
+            }
+            thread::sleep(EVENT_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        frame::Fragmenter,
+        host::{Connection, TIMEOUT},
+        memory_interface::MemoryInterface,
+        message::{
+            Block, Envelope, Message, Operation, Operations, Payload, Status, PROTOCOL_VERSION,
+        },
+        policy::{Access, Region},
+        usb::{BATCH_MAX_DELAY_US, EVENTS_MAX, FRAME_MAX_SIZE},
+    };
+    use postcard::{from_bytes, to_slice};
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    const RAM: u32 = 0x2000_0000;
+    const FLASH: u32 = 0x0800_0000;
+    const STATUS: u32 = 0x4000_0000;
+
+    const REGIONS: &[Region] = &[
+        Region::new(RAM, 0x200, Access::READ_WRITE),
+        Region::new(FLASH, 0x4000, Access::READ_WRITE),
+    ];
+
+    fn target_error(result: Result<impl std::fmt::Debug, Error>) -> Option<ErrorCode> {
+        match result {
+            Err(Error::Target(code)) => Some(code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_sim_memory_access() {
+        let memory = SimMemory::new()
+            .ram(RAM, 0x400)
+            .rom(FLASH, &[0x78, 0x56, 0x34, 0x12]);
+        let policy = AccessPolicy::new(REGIONS);
+        let target = SimTarget::new(memory).policy(policy).packet_size(16);
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        assert_eq!(16, connection.packet_size());
+        connection.try_write16(RAM + 2, 0xbeef).unwrap();
+        assert_eq!(0xbeef_0000, connection.try_read32(RAM).unwrap());
+        assert_eq!(0x1234_5678, connection.try_read32(FLASH).unwrap());
+        assert_eq!(
+            Some(ErrorCode::BusFault),
+            target_error(connection.try_write32(FLASH, 0))
+        );
+        assert_eq!(
+            Some(ErrorCode::BusFault),
+            target_error(connection.try_read32(FLASH + 4))
+        );
+        assert_eq!(
+            Some(ErrorCode::Forbidden),
+            target_error(connection.try_read32(RAM + 0x200))
+        );
+
+        let bytes: Vec<u8> = (0..200).collect();
+        let mut read = vec![0; bytes.len()];
+        connection.write_block(RAM + 0x10, &bytes).unwrap();
+        connection.read_block(RAM + 0x10, &mut read).unwrap();
+        assert_eq!(bytes, read);
+
+        let results = connection
+            .batch()
+            .write32(RAM, 1)
+            .modify32(RAM, 0xf0, 0x30)
+            .delay_us(10)
+            .read32(RAM)
+            .execute()
+            .unwrap();
+        assert_eq!(vec![Data::U32(0x31)], results);
+        assert_eq!(
+            Some(0x31),
+            connection.transport().memory(|memory| memory.peek32(RAM))
+        );
+
+        // Long delays are sent in several batches, the target rejects
+        // batches delaying for longer than its budget
+        let results = connection
+            .batch()
+            .delay_us(2 * BATCH_MAX_DELAY_US + 1)
+            .read32(RAM)
+            .execute()
+            .unwrap();
+        assert_eq!(vec![Data::U32(0x31)], results);
+        let delay = Operation::Delay(BATCH_MAX_DELAY_US / 2 + 1);
+        let batch = Message::Batch(Operations::from_slice(&[delay, delay]).unwrap());
+        assert_eq!(
+            Message::BatchError {
+                index: 1,
+                error: ErrorCode::UnsupportedSize
+            },
+            connection.request(batch).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sim_block_accesses() {
+        const FIFO: u32 = 0x4000_0100;
+        const WORD_REGIONS: &[Region] = &[
+            Region::new(RAM, 0x10, Access::READ_WRITE),
+            Region::new(FIFO, 4, Access::READ.union(Access::WIDTH_32)),
+        ];
+        let pops = Arc::new(AtomicU32::new(0));
+        let counter = pops.clone();
+        let fifo = Register::new(0x1122_3300)
+            .on_read(move |value| value + counter.fetch_add(1, Ordering::Relaxed));
+        let memory = SimMemory::new().ram(RAM, 0x10).register(FIFO, fifo);
+        let policy = AccessPolicy::new(WORD_REGIONS);
+        let target = SimTarget::new(memory).policy(policy);
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        // Word aligned blocks are accessed word by word, a FIFO pops once
+        let mut word = [0; 4];
+        connection.read_block(FIFO, &mut word).unwrap();
+        assert_eq!(0x1122_3300u32.to_le_bytes(), word);
+        assert_eq!(1, pops.load(Ordering::Relaxed));
+        assert_eq!(
+            Some(ErrorCode::Forbidden),
+            target_error(connection.read_block(FIFO + 1, &mut word[..2]))
+        );
+
+        // Unaligned head and tail are accessed byte by byte
+        let bytes: Vec<u8> = (1..=13).collect();
+        connection.write_block(RAM + 1, &bytes).unwrap();
+        let mut read = vec![0; 15];
+        connection.read_block(RAM, &mut read).unwrap();
+        assert_eq!([&[0][..], &bytes, &[0]].concat(), read);
+
+        // Raw requests wrapping around the address space are rejected
+        let target = SimTarget::new(SimMemory::new());
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+        let request = Message::ReadBlock {
+            address: 0xffff_fff0,
+            len: 0x20,
+        };
+        assert_eq!(
+            Some(ErrorCode::Forbidden),
+            target_error(connection.request(request))
+        );
+    }
+
+    #[test]
+    fn test_sim_register_side_effects() {
+        let reads = Arc::new(AtomicU32::new(0));
+        let counter = reads.clone();
+        // Ready after a few reads, write one to clear
+        let status = Register::new(0)
+            .on_read(move |value| {
+                let reads = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                if reads >= 3 {
+                    value | 1
+                } else {
+                    value
+                }
+            })
+            .on_write(|value, written| value & !written);
+        let target = SimTarget::new(SimMemory::new().register(STATUS, status));
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        assert_eq!(
+            1,
+            connection
+                .wait_for32(STATUS, 1, 1, Duration::from_millis(100))
+                .unwrap()
+        );
+        assert!(reads.load(Ordering::Relaxed) >= 3);
+
+        connection
+            .transport()
+            .memory(|memory| memory.poke32(STATUS, 0b110));
+        connection.try_write32(STATUS, 0b010).unwrap();
+        assert_eq!(
+            Some(0b100),
+            connection.transport().memory(|m| m.peek32(STATUS))
+        );
+
+        // Byte writes clear bits of the written byte only
+        connection
+            .transport()
+            .memory(|memory| memory.poke32(STATUS + 1, 0x0106));
+        connection.try_write8(STATUS, 0x02).unwrap();
+        assert_eq!(
+            Some(0x0104),
+            connection.transport().memory(|m| m.peek32(STATUS + 2))
+        );
+        connection.try_write8(STATUS + 1, 0x01).unwrap();
+        assert_eq!(
+            Some(0x0004),
+            connection.transport().memory(|m| m.peek32(STATUS))
+        );
+    }
+
+    /// Sum of `len` bytes starting from `address`
+    fn checksum(memory: &mut SimMemory, args: &[u8], reply: &mut Payload) -> Result<(), ErrorCode> {
+        let (address, len): (u32, u32) = from_bytes(args).map_err(|_| ErrorCode::Decode)?;
+        let mut bytes = vec![0; len as usize];
+        memory.read_bytes(address, &mut bytes)?;
+        let sum = bytes.iter().map(|byte| *byte as u32).sum::<u32>();
+        let mut buf = [0; 8];
+        let bytes = to_slice(&sum, &mut buf).map_err(|_| ErrorCode::UnsupportedSize)?;
+        reply
+            .extend_from_slice(bytes)
+            .map_err(|_| ErrorCode::UnsupportedSize)
+    }
+
+    #[test]
+    fn test_sim_custom_command() {
+        let target = SimTarget::new(SimMemory::new().ram(RAM, 0x100)).command(1, checksum);
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        connection.write_block(RAM, &[1, 2, 3, 250]).unwrap();
+        assert_eq!(256u32, connection.call(1, &(RAM, 4u32)).unwrap());
+        assert_eq!(
+            Some(ErrorCode::BusFault),
+            target_error(connection.call::<_, u32>(1, &(RAM, 0x101u32)))
+        );
+        assert_eq!(
+            Some(ErrorCode::Unsupported),
+            target_error(connection.call::<_, u32>(2, &()))
+        );
+    }
+
+    #[test]
+    fn test_sim_events() {
+        let target = SimTarget::new(SimMemory::new());
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        // Overflow the queue before anyone reads it
+        let gpio = |line| Event::Gpio { line, rising: true };
+        for line in 0..EVENTS_MAX as u8 + 2 {
+            assert_eq!(
+                line < EVENTS_MAX as u8,
+                connection.transport().push_event(gpio(line))
+            );
+        }
+
+        let events = connection.subscribe().unwrap();
+        for line in 0..EVENTS_MAX as u8 {
+            assert_eq!(gpio(line), events.recv_timeout(TIMEOUT).unwrap());
+        }
+        assert_eq!(Event::Lost(2), events.recv_timeout(TIMEOUT).unwrap());
+
+        // Events spanning several packets
+        let payload = Payload::from_slice(&[0x5a; 100]).unwrap();
+        let custom = Event::Custom { id: 7, payload };
+        connection.transport().push_event(custom.clone());
+        assert_eq!(custom, events.recv_timeout(TIMEOUT).unwrap());
+    }
+
+    #[test]
+    fn test_sim_interrupts() {
+        let target = SimTarget::new(SimMemory::new()).forward_interrupts(&[40]);
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        assert!(connection
+            .capabilities()
+            .supports(Capabilities::FEATURE_INTERRUPTS));
+        assert_eq!(
+            Some(ErrorCode::Forbidden),
+            target_error(connection.enable_interrupt(41))
+        );
+        assert!(!connection.transport().raise_interrupt(40));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        connection
+            .on_interrupt(40, move |interrupt| sender.send(interrupt).unwrap())
+            .unwrap();
+        assert!(connection.transport().raise_interrupt(40));
+        assert_eq!(40, receiver.recv_timeout(TIMEOUT).unwrap().irq);
+
+        connection.disable_interrupt(40).unwrap();
+        assert!(!connection.transport().raise_interrupt(40));
+    }
+
+    #[test]
+    fn test_sim_control_requests() {
+        let target = SimTarget::new(SimMemory::new().ram(RAM, 0x100));
+        let connection = Connection::open(target, TIMEOUT).unwrap();
+
+        assert_eq!(PROTOCOL_VERSION, connection.protocol_version().unwrap());
+        assert_eq!(Status::default(), connection.status().unwrap());
+        assert!(matches!(
+            connection.transport().control_out(0x7f, TIMEOUT),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(
+            connection.transport().send_packet(&[0; 65], TIMEOUT),
+            Err(Error::Overflow)
+        ));
+        // Simulated firmware does not allow reboots
+        assert!(matches!(connection.reboot(), Err(Error::Unsupported)));
+
+        // Request left half sent
+        let envelope = Envelope::new(
+            1,
+            Message::WriteBlock {
+                address: RAM,
+                bytes: Block::from_slice(&[0; 32]).unwrap(),
+            },
+        );
+        let mut fragmenter = Fragmenter::<FRAME_MAX_SIZE>::new();
+        fragmenter.load(&envelope).unwrap();
+        let mut buf = [0; DEFAULT_PACKET_SIZE];
+        let size = fragmenter.next_packet(&mut buf[..16]).unwrap();
+        connection
+            .transport()
+            .send_packet(&buf[..size], TIMEOUT)
+            .unwrap();
+        assert!(connection.status().unwrap().receiving);
+
+        connection.reset_pipe().unwrap();
+        assert_eq!(Status::default(), connection.status().unwrap());
+        assert!(connection.ready_to_use());
+    }
+}
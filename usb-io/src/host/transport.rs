@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use rusb::{Context, DeviceHandle, Direction, Recipient, RequestType};
+
+use crate::{
+    host::{Device, Error},
+    usb::{PACKET_MAX_SIZE, USB_IO_EVENT_ENDPOINT, USB_IO_IN_ENDPOINT, USB_IO_OUT_ENDPOINT},
+};
+
+/// Number of times to retry a bulk packet receive operation before giving up
+const MAX_RECV_RETRIES: usize = 3;
+
+/// Packet link to a USB-IO target, the protocol is run on top of it by `Connection`.
+///
+/// Packets of a message are sent and received under a lock held by
+/// `Connection`, so implementations need no message level synchronization.
+pub trait Transport: Send + Sync {
+    /// Size of packets messages are split into
+    fn max_packet_size(&self) -> usize;
+
+    /// Send single packet of at most `max_packet_size` bytes
+    fn send_packet(&self, packet: &[u8], timeout: Duration) -> Result<(), Error>;
+
+    /// Receive single packet into `buf`, returns its size.
+    ///
+    /// Callers pass buffers of the packet size: a larger buffer lets the read
+    /// run on into the next packet, as USB bulk reads end at short packets only.
+    fn recv_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error>;
+
+    /// Receive single packet of events pushed by the target into `buf`,
+    /// called concurrently with the methods above. Buffers are sized like
+    /// the ones of `recv_packet`.
+    fn recv_event_packet(&self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Vendor control request `request` reading its reply into `buf`,
+    /// bypasses the packet link
+    fn control_in(
+        &self,
+        _request: u8,
+        _buf: &mut [u8],
+        _timeout: Duration,
+    ) -> Result<usize, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Vendor control request `request` without data, bypasses the packet link
+    fn control_out(&self, _request: u8, _timeout: Duration) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Clear the halt of the endpoints of the packet link after the target
+    /// stalled them, links without halts have nothing to clear
+    fn clear_halt(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Transport over USB bulk endpoints
+pub struct UsbTransport {
+    /// Handle to the underlying USB device
+    handle: DeviceHandle<Context>,
+
+    /// USB-IO device this transport is connected to
+    device: Device,
+
+    /// Size of bulk packets, discovered from the endpoint descriptor
+    packet_size: usize,
+}
+
+impl UsbTransport {
+    /// Open transport to USB-IO device
+    pub fn open(device: Device) -> Result<Self, Error> {
+        let handle = device.open_handle()?;
+        let packet_size = device.max_packet_size()?.min(PACKET_MAX_SIZE);
+
+        Ok(Self {
+            handle,
+            device,
+            packet_size,
+        })
+    }
+
+    /// Borrow the `Device` for this transport
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl Transport for UsbTransport {
+    fn max_packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    fn send_packet(&self, packet: &[u8], timeout: Duration) -> Result<(), Error> {
+        let nbytes = self
+            .handle
+            .write_bulk(USB_IO_OUT_ENDPOINT, packet, timeout)?;
+
+        if nbytes != packet.len() {
+            return Err(Error::Usb(rusb::Error::Io));
+        }
+        Ok(())
+    }
+
+    fn recv_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let mut attempts = 1;
+        loop {
+            match self.handle.read_bulk(USB_IO_IN_ENDPOINT, buf, timeout) {
+                Ok(size) => return Ok(size),
+
+                // Sometimes I/O errors occur sporadically. When this happens,
+                // retry the read for `MAX_RECV_RETRIES` attempts
+                Err(rusb::Error::Io) if attempts < MAX_RECV_RETRIES => {
+                    attempts += 1;
+                    log!(
+                        warn,
+                        attempts_remaining = MAX_RECV_RETRIES - attempts + 1,
+                        "I/O error during USB bulk message receive, retrying"
+                    );
+                }
+                // All other errors we return immediately
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn recv_event_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let size = self
+            .handle
+            .read_interrupt(USB_IO_EVENT_ENDPOINT, buf, timeout)?;
+        Ok(size)
+    }
+
+    fn control_in(&self, request: u8, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let request_type =
+            rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface);
+        let size = self
+            .handle
+            .read_control(request_type, request, 0, 0, buf, timeout)
+            .map_err(control_error)?;
+        Ok(size)
+    }
+
+    fn control_out(&self, request: u8, timeout: Duration) -> Result<(), Error> {
+        let request_type =
+            rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface);
+        self.handle
+            .write_control(request_type, request, 0, 0, &[], timeout)
+            .map_err(control_error)?;
+        Ok(())
+    }
+
+    fn clear_halt(&self) -> Result<(), Error> {
+        self.handle.clear_halt(USB_IO_OUT_ENDPOINT)?;
+        self.handle.clear_halt(USB_IO_IN_ENDPOINT)?;
+        Ok(())
+    }
+}
+
+/// The target stalls control requests it does not handle
+fn control_error(err: rusb::Error) -> Error {
+    match err {
+        rusb::Error::Pipe => Error::Unsupported,
+        err => err.into(),
+    }
+}
@@ -2,6 +2,12 @@
 
 pub mod class;
 
+pub mod config;
+
+pub mod frame;
+
+pub mod handler;
+
 #[cfg(feature = "std")]
 pub mod host;
 
@@ -9,6 +15,18 @@ pub mod memory_interface;
 
 pub mod message;
 
+mod msos;
+
+pub mod policy;
+
+pub mod probe;
+
+mod target;
+
 pub mod usb;
 
+pub use handler::TargetHandler;
 pub use memory_interface::{InfallibleMemoryInterface, MemoryInterface};
+
+#[cfg(feature = "async")]
+pub use memory_interface::AsyncMemoryInterface;
@@ -1,4 +1,6 @@
 use core::fmt::Debug;
+#[cfg(feature = "async")]
+use core::future::Future;
 pub trait MemoryInterface {
     type Error;
 
@@ -9,6 +11,20 @@ pub trait MemoryInterface {
     fn try_write8(&self, address: u32, value: u8) -> Result<(), Self::Error>;
     fn try_write16(&self, address: u32, value: u16) -> Result<(), Self::Error>;
     fn try_write32(&self, address: u32, value: u32) -> Result<(), Self::Error>;
+
+    /// Replace bits selected by `mask` with bits of `value`
+    fn try_modify32(&self, address: u32, mask: u32, value: u32) -> Result<(), Self::Error> {
+        let current = self.try_read32(address)?;
+        self.try_write32(address, current & !mask | value & mask)
+    }
+
+    fn try_set_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        self.try_modify32(address, bits, bits)
+    }
+
+    fn try_clear_bits32(&self, address: u32, bits: u32) -> Result<(), Self::Error> {
+        self.try_modify32(address, bits, 0)
+    }
 }
 
 pub trait InfallibleMemoryInterface {
@@ -19,6 +35,10 @@ pub trait InfallibleMemoryInterface {
     fn write8(&self, address: u32, value: u8);
     fn write16(&self, address: u32, value: u16);
     fn write32(&self, address: u32, value: u32);
+
+    fn modify32(&self, address: u32, mask: u32, value: u32);
+    fn set_bits32(&self, address: u32, bits: u32);
+    fn clear_bits32(&self, address: u32, bits: u32);
 }
 
 impl<E, T> InfallibleMemoryInterface for T
@@ -49,4 +69,72 @@ where
     fn write32(&self, address: u32, value: u32) {
         self.try_write32(address, value).unwrap()
     }
+
+    fn modify32(&self, address: u32, mask: u32, value: u32) {
+        self.try_modify32(address, mask, value).unwrap()
+    }
+
+    fn set_bits32(&self, address: u32, bits: u32) {
+        self.try_set_bits32(address, bits).unwrap()
+    }
+
+    fn clear_bits32(&self, address: u32, bits: u32) {
+        self.try_clear_bits32(address, bits).unwrap()
+    }
+}
+
+/// Memory access of targets which are reached without blocking the caller
+#[cfg(feature = "async")]
+pub trait AsyncMemoryInterface: Sync {
+    type Error: Send;
+
+    fn try_read8(&self, address: u32) -> impl Future<Output = Result<u8, Self::Error>> + Send;
+    fn try_read16(&self, address: u32) -> impl Future<Output = Result<u16, Self::Error>> + Send;
+    fn try_read32(&self, address: u32) -> impl Future<Output = Result<u32, Self::Error>> + Send;
+
+    fn try_write8(
+        &self,
+        address: u32,
+        value: u8,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn try_write16(
+        &self,
+        address: u32,
+        value: u16,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn try_write32(
+        &self,
+        address: u32,
+        value: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Replace bits selected by `mask` with bits of `value`
+    fn try_modify32(
+        &self,
+        address: u32,
+        mask: u32,
+        value: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move {
+            let current = self.try_read32(address).await?;
+            self.try_write32(address, current & !mask | value & mask)
+                .await
+        }
+    }
+
+    fn try_set_bits32(
+        &self,
+        address: u32,
+        bits: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.try_modify32(address, bits, bits)
+    }
+
+    fn try_clear_bits32(
+        &self,
+        address: u32,
+        bits: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.try_modify32(address, bits, 0)
+    }
 }
@@ -1,20 +1,175 @@
+use core::fmt;
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+use crate::usb::{BATCH_MAX_OPS, BLOCK_MAX_SIZE, PAYLOAD_MAX_SIZE};
+
+/// Version of the wire protocol, changed only when hosts and targets of
+/// different versions can no longer talk, additions are announced with
+/// `Capabilities::FEATURE_*` bits
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Payload of block transfers
+pub type Block = Vec<u8, BLOCK_MAX_SIZE>;
+
+/// Operations of a batch
+pub type Operations = Vec<Operation, BATCH_MAX_OPS>;
+
+/// Values read by a batch
+pub type BatchData = Vec<Data, BATCH_MAX_OPS>;
+
+/// Arguments and results of custom commands
+pub type Payload = Vec<u8, PAYLOAD_MAX_SIZE>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Data {
     U8(u8),
     U16(u16),
     U32(u32),
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum DataSize {
     U8,
     U16,
     U32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+impl Data {
+    /// Size of the contained value
+    pub fn size(&self) -> DataSize {
+        match self {
+            Data::U8(_) => DataSize::U8,
+            Data::U16(_) => DataSize::U16,
+            Data::U32(_) => DataSize::U32,
+        }
+    }
+}
+
+impl DataSize {
+    /// Number of bytes accessed
+    pub fn bytes(&self) -> u32 {
+        match self {
+            DataSize::U8 => 1,
+            DataSize::U16 => 2,
+            DataSize::U32 => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// Request could not be decoded
+    Decode,
+    /// Address is not aligned to the access size
+    Unaligned,
+    /// Address is outside of the allowed regions
+    Forbidden,
+    /// Access size or length is not supported
+    UnsupportedSize,
+    /// Access caused a bus fault
+    BusFault,
+    /// Request is not supported by the target
+    Unsupported,
+    /// Condition was not met in time
+    Timeout,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::Decode => "request could not be decoded",
+            ErrorCode::Unaligned => "unaligned address",
+            ErrorCode::Forbidden => "forbidden region",
+            ErrorCode::UnsupportedSize => "unsupported size",
+            ErrorCode::BusFault => "bus fault",
+            ErrorCode::Unsupported => "unsupported request",
+            ErrorCode::Timeout => "timeout",
+        })
+    }
+}
+
+/// Single operation of a `Message::Batch`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Operation {
+    /// Set address to data
+    Set(u32, Data),
+    /// Get from address to data size, the value is added to the batch result
+    Get(u32, DataSize),
+    /// Replace bits selected by mask with bits of value
+    Modify {
+        address: u32,
+        mask: u32,
+        value: u32,
+        width: DataSize,
+    },
+    /// Busy wait for microseconds, the delays of a batch add up to at most
+    /// `BATCH_MAX_DELAY_US`
+    Delay(u32),
+}
+
+/// Target description returned in response to `Message::Hello`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Version of the wire protocol
+    pub protocol_version: u16,
+    /// Size of bulk packets
+    pub max_packet_size: u16,
+    /// Supported data widths, bit `n` stands for `2^n` bytes wide accesses
+    pub widths: u8,
+    /// Firmware build identifier
+    pub build_id: u32,
+    /// Optional features, see `Capabilities::FEATURE_*`
+    pub features: u32,
+}
+
+impl Capabilities {
+    /// `ReadBlock` / `WriteBlock` messages
+    pub const FEATURE_BLOCK: u32 = 1 << 0;
+    /// Accesses are checked against an access policy
+    pub const FEATURE_ACCESS_POLICY: u32 = 1 << 1;
+    /// Bus faults are caught and reported as errors
+    pub const FEATURE_BUS_FAULT: u32 = 1 << 2;
+    /// `Modify`, `SetBits` and `ClearBits` messages
+    pub const FEATURE_MODIFY: u32 = 1 << 3;
+    /// `WaitFor` message
+    pub const FEATURE_WAIT_FOR: u32 = 1 << 4;
+    /// `Batch` message
+    pub const FEATURE_BATCH: u32 = 1 << 5;
+    /// `Custom` message
+    pub const FEATURE_CUSTOM: u32 = 1 << 6;
+    /// Events are pushed over the event endpoint
+    pub const FEATURE_EVENTS: u32 = 1 << 7;
+    /// `EnableInterrupt` and `DisableInterrupt` messages, occurrences are
+    /// reported with `Event::Interrupt`
+    pub const FEATURE_INTERRUPTS: u32 = 1 << 8;
+
+    /// All data widths
+    pub const ALL_WIDTHS: u8 = 0b111;
+
+    /// Capabilities assumed until the target answered `Message::Hello`
+    pub const fn basic(max_packet_size: u16) -> Self {
+        Self {
+            protocol_version: 0,
+            max_packet_size,
+            widths: Self::ALL_WIDTHS,
+            build_id: 0,
+            features: 0,
+        }
+    }
+
+    /// Target supports all of the `features`
+    pub fn supports(&self, features: u32) -> bool {
+        self.features & features == features
+    }
+
+    /// Target supports accesses of data size
+    pub fn supports_width(&self, size: DataSize) -> bool {
+        self.widths & size.bytes() as u8 != 0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum Message {
     /// Ping ask
     Ping,
@@ -30,19 +185,158 @@ pub enum Message {
     Get(u32, DataSize),
     /// No operation (used for coverage testing and performance metrics)
     Nop,
+    /// Read up to `BLOCK_MAX_SIZE` bytes starting from address
+    ReadBlock { address: u32, len: u16 },
+    /// Write bytes starting from address
+    WriteBlock { address: u32, bytes: Block },
+    /// Block data response
+    Block(Block),
+    /// Error response
+    Error(ErrorCode),
+    /// Ask for target capabilities
+    Hello,
+    /// Capabilities response
+    Capabilities(Capabilities),
+    /// Replace bits selected by mask with bits of value, atomically on the target
+    Modify {
+        address: u32,
+        mask: u32,
+        value: u32,
+        width: DataSize,
+    },
+    /// Set bits, atomically on the target
+    SetBits {
+        address: u32,
+        bits: u32,
+        width: DataSize,
+    },
+    /// Clear bits, atomically on the target
+    ClearBits {
+        address: u32,
+        bits: u32,
+        width: DataSize,
+    },
+    /// Poll word at address until `value & mask == expected`, answered with
+    /// the last read value or `ErrorCode::Timeout`
+    WaitFor {
+        address: u32,
+        mask: u32,
+        expected: u32,
+        timeout_us: u32,
+    },
+    /// Execute operations in order, answered with `BatchResult` or `BatchError`
+    Batch(Operations),
+    /// Values read by `Operation::Get` of a batch, in order
+    BatchResult(BatchData),
+    /// Batch stopped at the operation with index
+    BatchError { index: u8, error: ErrorCode },
+    /// Application defined command, answered with `Custom` carrying the same id
+    Custom { id: u16, payload: Payload },
+    /// Forward occurrences of interrupt line to the host
+    EnableInterrupt(u8),
+    /// Stop forwarding interrupt line
+    DisableInterrupt(u8),
+}
+
+impl Message {
+    /// Name of the variant, for diagnostics without the payload
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Ping => "Ping",
+            Self::Pong => "Pong",
+            Self::Ack => "Ack",
+            Self::Data(_) => "Data",
+            Self::Set(..) => "Set",
+            Self::Get(..) => "Get",
+            Self::Nop => "Nop",
+            Self::ReadBlock { .. } => "ReadBlock",
+            Self::WriteBlock { .. } => "WriteBlock",
+            Self::Block(_) => "Block",
+            Self::Error(_) => "Error",
+            Self::Hello => "Hello",
+            Self::Capabilities(_) => "Capabilities",
+            Self::Modify { .. } => "Modify",
+            Self::SetBits { .. } => "SetBits",
+            Self::ClearBits { .. } => "ClearBits",
+            Self::WaitFor { .. } => "WaitFor",
+            Self::Batch(_) => "Batch",
+            Self::BatchResult(_) => "BatchResult",
+            Self::BatchError { .. } => "BatchError",
+            Self::Custom { .. } => "Custom",
+            Self::EnableInterrupt(_) => "EnableInterrupt",
+            Self::DisableInterrupt(_) => "DisableInterrupt",
+        }
+    }
+}
+
+/// State of the bulk pipe, answer to the `REQUEST_STATUS` control request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct Status {
+    /// A request is partially received
+    pub receiving: bool,
+    /// A response is being sent, the next request is held off until it is read
+    pub responding: bool,
+    /// A `WaitFor` request is pending
+    pub waiting: bool,
+    /// Number of events waiting to be sent
+    pub events: u8,
+}
+
+/// Occurrence of a forwarded interrupt
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Interrupt {
+    /// Interrupt line (NVIC IRQ number)
+    pub irq: u8,
+    /// Target time of the occurrence in microseconds, wraps around
+    pub timestamp_us: u32,
+}
+
+/// Notification pushed by the target over the event endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// Edge detected on a GPIO line
+    Gpio { line: u8, rising: bool },
+    /// Watched word at address changed to value
+    Changed { address: u32, value: u32 },
+    /// Buffer with id holds len bytes of data
+    BufferReady { id: u16, len: u32 },
+    /// Firmware fault
+    Fault(ErrorCode),
+    /// Application defined event
+    Custom { id: u16, payload: Payload },
+    /// Number of events dropped because the event queue was full
+    Lost(u16),
+    /// Forwarded interrupt occurred
+    Interrupt(Interrupt),
+}
+
+/// Message framed with a tag, the target echoes the tag of a request in its response
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Envelope {
+    pub tag: u16,
+    pub message: Message,
+}
+
+impl Envelope {
+    /// Tag of responses to requests whose own tag could not be decoded
+    pub const UNTAGGED: u16 = 0;
+
+    pub fn new(tag: u16, message: Message) -> Self {
+        Self { tag, message }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::usb::MESSAGE_MAX_SIZE;
+    use crate::usb::PACKET_MAX_SIZE;
     use postcard::{from_bytes, to_slice};
 
     #[test]
     fn test_encode_decode_message() {
         let message = Message::Ping;
-        let mut buf = [0u8; MESSAGE_MAX_SIZE as usize];
+        let mut buf = [0u8; PACKET_MAX_SIZE];
         let slice = to_slice(&message, &mut buf).unwrap();
-        assert_eq!(message, from_bytes(&slice).unwrap());
+        assert_eq!(message, from_bytes(slice).unwrap());
     }
 }
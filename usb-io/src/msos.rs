@@ -0,0 +1,158 @@
+//! Microsoft OS 2.0 descriptors binding the WinUSB driver without an INF file.
+//!
+//! Windows reads the platform capability of the BOS descriptor and then
+//! fetches the descriptor set with a vendor request carrying `VENDOR_CODE`.
+
+/// `bRequest` of the vendor request returning the descriptor set
+pub const VENDOR_CODE: u8 = 0x20;
+
+/// `wIndex` of the vendor request returning the descriptor set
+pub const DESCRIPTOR_INDEX: u16 = 7;
+
+/// Minimal Windows version supporting the descriptors, Windows 8.1
+const WINDOWS_VERSION: u32 = 0x0603_0000;
+
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F} in wire order
+const PLATFORM_CAPABILITY_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+const SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const FEATURE_REG_PROPERTY: u16 = 0x04;
+
+/// `REG_MULTI_SZ`
+const PROPERTY_DATA_TYPE: u16 = 0x07;
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+
+/// Length of a GUID in registry format, `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`
+pub const GUID_LEN: usize = 38;
+
+const HEADER_LEN: usize = 10;
+const COMPATIBLE_ID_LEN: usize = 20;
+/// Name is terminated by a null, data by two of them
+const REG_PROPERTY_LEN: usize = 10 + (PROPERTY_NAME.len() + 1) * 2 + (GUID_LEN + 2) * 2;
+
+/// Length of the descriptor set
+pub const SET_LEN: usize = HEADER_LEN + COMPATIBLE_ID_LEN + REG_PROPERTY_LEN;
+
+/// Data of the platform capability descriptor, following its capability type
+pub fn platform_capability() -> [u8; 25] {
+    let mut data = [0; 25];
+    data[1..17].copy_from_slice(&PLATFORM_CAPABILITY_UUID);
+    data[17..21].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+    data[21..23].copy_from_slice(&(SET_LEN as u16).to_le_bytes());
+    data[23] = VENDOR_CODE;
+    data
+}
+
+/// Write descriptor set binding WinUSB with device interface `guid` into
+/// `buf`, returns its length or `None` if `buf` is too small or `guid` is malformed
+pub fn write_descriptor_set(buf: &mut [u8], guid: &str) -> Option<usize> {
+    if buf.len() < SET_LEN || guid.len() != GUID_LEN {
+        return None;
+    }
+    let mut writer = Writer { buf, position: 0 };
+
+    writer.u16(HEADER_LEN as u16);
+    writer.u16(SET_HEADER_DESCRIPTOR);
+    writer.bytes(&WINDOWS_VERSION.to_le_bytes());
+    writer.u16(SET_LEN as u16);
+
+    writer.u16(COMPATIBLE_ID_LEN as u16);
+    writer.u16(FEATURE_COMPATIBLE_ID);
+    writer.bytes(b"WINUSB\0\0");
+    writer.bytes(&[0; 8]);
+
+    writer.u16(REG_PROPERTY_LEN as u16);
+    writer.u16(FEATURE_REG_PROPERTY);
+    writer.u16(PROPERTY_DATA_TYPE);
+    writer.u16(((PROPERTY_NAME.len() + 1) * 2) as u16);
+    writer.utf16(PROPERTY_NAME, 1);
+    writer.u16(((GUID_LEN + 2) * 2) as u16);
+    writer.utf16(guid, 2);
+
+    Some(writer.position)
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Write ASCII `s` as UTF-16LE followed by `nulls` null characters
+    fn utf16(&mut self, s: &str, nulls: usize) {
+        for c in s.bytes() {
+            self.u16(c as u16);
+        }
+        for _ in 0..nulls {
+            self.u16(0);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    const GUID: &str = "{4d36e978-e325-11ce-bfc1-08002be10318}";
+
+    #[test]
+    fn test_descriptor_set() {
+        let mut buf = [0; 256];
+        assert_eq!(Some(SET_LEN), write_descriptor_set(&mut buf, GUID));
+        assert_eq!(162, SET_LEN);
+        let set = &buf[..SET_LEN];
+
+        // Set header: length, type, Windows version, total length
+        assert_eq!(
+            [0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0xa2, 0x00],
+            set[..10]
+        );
+        // Compatible ID: length, type, compatible and sub-compatible ids
+        assert_eq!([0x14, 0x00, 0x03, 0x00], set[10..14]);
+        assert_eq!(b"WINUSB\0\0\0\0\0\0\0\0\0\0", &set[14..30]);
+        // Registry property: length, type, REG_MULTI_SZ, name length
+        assert_eq!(
+            [0x84, 0x00, 0x04, 0x00, 0x07, 0x00, 0x2a, 0x00],
+            set[30..38]
+        );
+
+        let utf16 = |bytes: &[u8]| -> String {
+            let units: Vec<u16> = bytes
+                .chunks(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16(&units).unwrap()
+        };
+        assert_eq!("DeviceInterfaceGUIDs\0", utf16(&set[38..80]));
+        assert_eq!([0x50, 0x00], set[80..82]);
+        assert_eq!(format!("{}\0\0", GUID), utf16(&set[82..]));
+
+        assert_eq!(None, write_descriptor_set(&mut buf[..SET_LEN - 1], GUID));
+        assert_eq!(None, write_descriptor_set(&mut buf, "{4d36e978}"));
+    }
+
+    #[test]
+    fn test_platform_capability() {
+        let data = platform_capability();
+        // Reserved, UUID
+        assert_eq!([0x00, 0xdf, 0x60, 0xdd, 0xd8], data[..5]);
+        assert_eq!([0x8a, 0x9f], data[15..17]);
+        // Windows version, set length, vendor code, no alternate enumeration
+        assert_eq!(
+            [0x00, 0x00, 0x03, 0x06, 0xa2, 0x00, VENDOR_CODE, 0x00],
+            data[17..]
+        );
+    }
+}
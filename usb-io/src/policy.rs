@@ -0,0 +1,172 @@
+use core::ops::BitOr;
+
+use crate::message::{DataSize, ErrorCode};
+
+/// Permissions for accessing a memory region
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Access(u8);
+
+impl Access {
+    /// No access at all
+    pub const NONE: Access = Access(0);
+    /// Region can be read
+    pub const READ: Access = Access(0x01);
+    /// Region can be written
+    pub const WRITE: Access = Access(0x02);
+    /// Byte wide accesses are allowed (also used by block transfers)
+    pub const WIDTH_8: Access = Access(0x10);
+    /// Half word wide accesses are allowed
+    pub const WIDTH_16: Access = Access(0x20);
+    /// Word wide accesses are allowed
+    pub const WIDTH_32: Access = Access(0x40);
+    /// Accesses of any width are allowed
+    pub const ANY_WIDTH: Access = Access(0x70);
+    /// Reads of any width
+    pub const READ_ONLY: Access = Access::READ.union(Access::ANY_WIDTH);
+    /// Reads and writes of any width
+    pub const READ_WRITE: Access = Access::READ_ONLY.union(Access::WRITE);
+
+    pub const fn union(self, other: Access) -> Access {
+        Access(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Access) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Width permission required for access of data size
+    pub const fn width(size: DataSize) -> Access {
+        match size {
+            DataSize::U8 => Access::WIDTH_8,
+            DataSize::U16 => Access::WIDTH_16,
+            DataSize::U32 => Access::WIDTH_32,
+        }
+    }
+}
+
+impl BitOr for Access {
+    type Output = Access;
+
+    fn bitor(self, rhs: Access) -> Access {
+        self.union(rhs)
+    }
+}
+
+/// Memory region with its access permissions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Region {
+    /// First address of the region
+    pub start: u32,
+    /// Size of the region in bytes
+    pub len: u32,
+    /// Allowed accesses
+    pub access: Access,
+}
+
+impl Region {
+    pub const fn new(start: u32, len: u32, access: Access) -> Self {
+        Self { start, len, access }
+    }
+
+    /// Whole range `address..address + len` lies inside of the region
+    pub fn contains(&self, address: u32, len: u32) -> bool {
+        address >= self.start && (address - self.start) as u64 + len as u64 <= self.len as u64
+    }
+}
+
+/// Set of memory regions the host is allowed to access
+#[derive(Clone, Copy, Debug)]
+pub struct AccessPolicy {
+    /// Allowed regions, `None` allows everything
+    regions: Option<&'static [Region]>,
+}
+
+impl AccessPolicy {
+    /// Allow only accesses which fit into one of the regions
+    pub const fn new(regions: &'static [Region]) -> Self {
+        Self {
+            regions: Some(regions),
+        }
+    }
+
+    /// Allow access to the whole address space
+    pub const fn allow_all() -> Self {
+        Self { regions: None }
+    }
+
+    /// Check access of `len` bytes with `access` permissions starting from `address`
+    pub fn check(&self, address: u32, len: u32, access: Access) -> Result<(), ErrorCode> {
+        let regions = match self.regions {
+            Some(regions) => regions,
+            None => return Ok(()),
+        };
+
+        if regions
+            .iter()
+            .any(|region| region.access.contains(access) && region.contains(address, len))
+        {
+            Ok(())
+        } else {
+            Err(ErrorCode::Forbidden)
+        }
+    }
+
+    /// Check single read of data size
+    pub fn check_read(&self, address: u32, size: DataSize) -> Result<(), ErrorCode> {
+        self.check(address, size.bytes(), Access::READ | Access::width(size))
+    }
+
+    /// Check single write of data size
+    pub fn check_write(&self, address: u32, size: DataSize) -> Result<(), ErrorCode> {
+        self.check(address, size.bytes(), Access::WRITE | Access::width(size))
+    }
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REGIONS: &[Region] = &[
+        Region::new(0x2000_0000, 0x1_0000, Access::READ_WRITE),
+        Region::new(
+            0x4002_0800,
+            0x400,
+            Access::READ.union(Access::WRITE).union(Access::WIDTH_32),
+        ),
+        Region::new(0x0800_0000, 0x4_0000, Access::READ_ONLY),
+    ];
+
+    #[test]
+    fn test_access_policy() {
+        let policy = AccessPolicy::new(REGIONS);
+
+        assert_eq!(Ok(()), policy.check_write(0x2000_fffc, DataSize::U32));
+        assert_eq!(
+            Err(ErrorCode::Forbidden),
+            policy.check_write(0x2000_fffe, DataSize::U32)
+        );
+        assert_eq!(Ok(()), policy.check_read(0x4002_0814, DataSize::U32));
+        assert_eq!(
+            Err(ErrorCode::Forbidden),
+            policy.check_read(0x4002_0814, DataSize::U8)
+        );
+        assert_eq!(
+            Err(ErrorCode::Forbidden),
+            policy.check_write(0x0800_0000, DataSize::U32)
+        );
+        assert_eq!(
+            Err(ErrorCode::Forbidden),
+            policy.check_read(0x4000_0000, DataSize::U32)
+        );
+        assert_eq!(
+            Ok(()),
+            AccessPolicy::allow_all().check_write(0x4000_0000, DataSize::U32)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Volatile memory accesses which survive bus faults.
+//!
+//! On Cortex-M the access runs with FAULTMASK set and `CCR.BFHFNMIGN`
+//! enabled, so a data bus fault does not escalate to HardFault but is only
+//! recorded in `CFSR`. Write buffering is disabled for the duration of the
+//! access to make every bus fault precise. Interrupts are masked while the
+//! access is in progress. On other architectures accesses are plain volatile
+//! reads and writes.
+
+use crate::message::{Data, DataSize, ErrorCode};
+
+/// Bus faults are caught on this architecture
+pub const CATCHES_BUS_FAULTS: bool = cfg!(all(target_arch = "arm", target_os = "none"));
+
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+mod arch {
+    use core::arch::asm;
+
+    use crate::message::ErrorCode;
+
+    const SCB_ACTLR: *mut u32 = 0xe000_e008 as *mut u32;
+    const SCB_CCR: *mut u32 = 0xe000_ed14 as *mut u32;
+    const SCB_CFSR: *mut u32 = 0xe000_ed28 as *mut u32;
+
+    /// Disable write buffer for default memory map
+    const ACTLR_DISDEFWBUF: u32 = 1 << 1;
+    /// Ignore data bus faults at priority -1 and -2
+    const CCR_BFHFNMIGN: u32 = 1 << 8;
+    /// BusFault status bits of CFSR
+    const CFSR_BFSR: u32 = 0xff << 8;
+
+    pub unsafe fn guarded<T>(access: impl FnOnce() -> T) -> Result<T, ErrorCode> {
+        // Drop stale fault status (write one to clear)
+        SCB_CFSR.write_volatile(CFSR_BFSR);
+
+        asm!("cpsid f");
+        let actlr = SCB_ACTLR.read_volatile();
+        let ccr = SCB_CCR.read_volatile();
+        SCB_ACTLR.write_volatile(actlr | ACTLR_DISDEFWBUF);
+        SCB_CCR.write_volatile(ccr | CCR_BFHFNMIGN);
+        asm!("dsb", "isb");
+
+        let value = access();
+
+        asm!("dsb", "isb");
+        SCB_CCR.write_volatile(ccr);
+        SCB_ACTLR.write_volatile(actlr);
+        asm!("dsb", "isb");
+        asm!("cpsie f");
+
+        let faulted = SCB_CFSR.read_volatile() & CFSR_BFSR != 0;
+        SCB_CFSR.write_volatile(CFSR_BFSR);
+
+        if faulted {
+            Err(ErrorCode::BusFault)
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "arm", target_os = "none")))]
+mod arch {
+    use crate::message::ErrorCode;
+
+    pub unsafe fn guarded<T>(access: impl FnOnce() -> T) -> Result<T, ErrorCode> {
+        Ok(access())
+    }
+}
+
+/// Run `access` with bus faults caught and interrupts masked
+///
+/// # Safety
+///
+/// `access` must not do anything but volatile accesses, faulting
+/// instructions are skipped and their results are undefined.
+pub(crate) unsafe fn guarded<T>(access: impl FnOnce() -> T) -> Result<T, ErrorCode> {
+    arch::guarded(access)
+}
+
+/// Read data of `size` from `address`
+///
+/// # Safety
+///
+/// Reading some registers has side effects.
+pub unsafe fn read(address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+    guarded(|| match size {
+        DataSize::U8 => Data::U8((address as *const u8).read_volatile()),
+        DataSize::U16 => Data::U16((address as *const u16).read_volatile()),
+        DataSize::U32 => Data::U32((address as *const u32).read_volatile()),
+    })
+}
+
+/// Write `data` to `address`
+///
+/// # Safety
+///
+/// Writing arbitrary memory can break the firmware.
+pub unsafe fn write(address: u32, data: Data) -> Result<(), ErrorCode> {
+    guarded(|| match data {
+        Data::U8(b) => (address as *mut u8).write_volatile(b),
+        Data::U16(b) => (address as *mut u16).write_volatile(b),
+        Data::U32(b) => (address as *mut u32).write_volatile(b),
+    })
+}
+
+/// Replace bits of `mask` at `address` with bits of `value` without being interrupted
+///
+/// # Safety
+///
+/// Writing arbitrary memory can break the firmware.
+pub unsafe fn modify(address: u32, size: DataSize, mask: u32, value: u32) -> Result<(), ErrorCode> {
+    guarded(|| match size {
+        DataSize::U8 => {
+            let ptr = address as *mut u8;
+            let (mask, value) = (mask as u8, value as u8);
+            ptr.write_volatile(ptr.read_volatile() & !mask | value & mask)
+        }
+        DataSize::U16 => {
+            let ptr = address as *mut u16;
+            let (mask, value) = (mask as u16, value as u16);
+            ptr.write_volatile(ptr.read_volatile() & !mask | value & mask)
+        }
+        DataSize::U32 => {
+            let ptr = address as *mut u32;
+            ptr.write_volatile(ptr.read_volatile() & !mask | value & mask)
+        }
+    })
+}
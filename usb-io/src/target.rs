@@ -0,0 +1,529 @@
+//! Target side of the protocol, independent of the USB stack.
+//!
+//! [`Target`] reassembles request frames from packets, dispatches requests to
+//! a [`TargetHandler`] after checking them against the access policy and
+//! hands out response packets. `UsbIoClass` drives it from the USB endpoints,
+//! the simulator drives it with emulated memory.
+
+use postcard::{from_bytes, take_from_bytes, to_slice};
+
+use crate::{
+    frame::{Assembler, Fragmenter},
+    handler::{block_spans, Command, TargetHandler},
+    message::{
+        BatchData, Block, Capabilities, Data, DataSize, Envelope, ErrorCode, Event, Message,
+        Operation, Payload, Status, PROTOCOL_VERSION,
+    },
+    policy::{Access, AccessPolicy},
+    usb::{
+        BATCH_MAX_DELAY_US, BLOCK_MAX_SIZE, COMMANDS_MAX, EVENTS_MAX, FRAME_MAX_SIZE,
+        REQUEST_RESET_PIPE, REQUEST_STATUS, REQUEST_VERSION,
+    },
+};
+
+/// Outcome of a received packet
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Received {
+    /// Frame is not complete yet
+    Incomplete,
+    /// Response is ready to be sent
+    Response,
+    /// `WaitFor` request was started, `poll_wait` loads the response
+    Waiting,
+}
+
+/// `WaitFor` request waiting for its condition
+struct PendingWait {
+    tag: u16,
+    address: u32,
+    mask: u32,
+    expected: u32,
+    remaining_us: u32,
+}
+
+/// Events waiting to be sent over the event endpoint
+pub(crate) struct Events {
+    queue: heapless::Deque<Event, EVENTS_MAX>,
+    tx: Fragmenter<FRAME_MAX_SIZE>,
+    lost: u16,
+}
+
+impl Events {
+    pub const fn new() -> Self {
+        Self {
+            queue: heapless::Deque::new(),
+            tx: Fragmenter::new(),
+            lost: 0,
+        }
+    }
+
+    /// Queue event, returns `false` if the queue is full and the event is dropped
+    pub fn push(&mut self, event: Event) -> bool {
+        if self.queue.push_back(event).is_err() {
+            self.lost = self.lost.saturating_add(1);
+            return false;
+        }
+        true
+    }
+
+    /// Write next packet of the event being sent into `buf`, starting the
+    /// next queued event when the previous one is sent
+    pub fn next_packet(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.tx.is_empty() {
+            // Dropped events were pushed after all queued ones
+            let event = match self.queue.pop_front() {
+                Some(event) => event,
+                None if self.lost > 0 => Event::Lost(core::mem::take(&mut self.lost)),
+                None => return None,
+            };
+            // Every event fits into a frame
+            self.tx.load(&event).ok()?;
+        }
+        self.tx.next_packet(buf)
+    }
+
+    /// Drop the rest of the event being sent
+    pub fn clear_current(&mut self) {
+        self.tx.clear();
+    }
+
+    /// Number of events waiting to be sent, including the one being sent
+    pub fn len(&self) -> usize {
+        self.queue.len() + !self.tx.is_empty() as usize
+    }
+
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        self.tx.clear();
+        self.lost = 0;
+    }
+}
+
+/// Interrupt lines the host may forward, bit `n` stands for IRQ `n`
+#[derive(Default)]
+pub(crate) struct Interrupts {
+    pub allowed: u128,
+    enabled: u128,
+}
+
+impl Interrupts {
+    fn bit(irq: u8) -> u128 {
+        1u128.checked_shl(irq as u32).unwrap_or(0)
+    }
+
+    /// Allow host to forward interrupt line `irq`
+    pub fn allow(&mut self, irq: u8) {
+        self.allowed |= Self::bit(irq);
+    }
+
+    /// Line `irq` is forwarded to the host
+    pub fn is_enabled(&self, irq: u8) -> bool {
+        self.enabled & Self::bit(irq) != 0
+    }
+}
+
+pub(crate) struct Target<H> {
+    pub handler: H,
+    pub policy: AccessPolicy,
+    pub build_id: u32,
+    pub packet_size: u16,
+    /// Next request is held off until the host read the last packet of the response
+    pub holding: bool,
+    rx: Assembler<FRAME_MAX_SIZE>,
+    tx: Fragmenter<FRAME_MAX_SIZE>,
+    wait: Option<PendingWait>,
+    commands: heapless::Vec<(u16, Command<H>), COMMANDS_MAX>,
+    pub events: Events,
+    pub interrupts: Interrupts,
+}
+
+impl<H: TargetHandler> Target<H> {
+    pub fn new(handler: H, policy: AccessPolicy, packet_size: u16) -> Self {
+        Self {
+            handler,
+            policy,
+            build_id: 0,
+            packet_size,
+            holding: false,
+            rx: Assembler::new(),
+            tx: Fragmenter::new(),
+            wait: None,
+            commands: heapless::Vec::new(),
+            events: Events::new(),
+            interrupts: Interrupts::default(),
+        }
+    }
+
+    /// Capabilities reported in response to `Message::Hello`
+    pub fn capabilities(&self) -> Capabilities {
+        let interrupts = if self.interrupts.allowed != 0 {
+            Capabilities::FEATURE_INTERRUPTS
+        } else {
+            0
+        };
+
+        Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            max_packet_size: self.packet_size,
+            widths: Capabilities::ALL_WIDTHS,
+            build_id: self.build_id,
+            features: Capabilities::FEATURE_BLOCK
+                | Capabilities::FEATURE_ACCESS_POLICY
+                | Capabilities::FEATURE_MODIFY
+                | Capabilities::FEATURE_WAIT_FOR
+                | Capabilities::FEATURE_BATCH
+                | Capabilities::FEATURE_CUSTOM
+                | Capabilities::FEATURE_EVENTS
+                | interrupts
+                | self.handler.features(),
+        }
+    }
+
+    /// Register custom command `id`, replacing the one registered before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than `COMMANDS_MAX` commands are registered.
+    pub fn register(&mut self, id: u16, command: Command<H>) {
+        match self.commands.iter_mut().find(|(known, _)| *known == id) {
+            Some(entry) => entry.1 = command,
+            None => {
+                if self.commands.push((id, command)).is_err() {
+                    panic!("too many custom commands");
+                }
+            }
+        }
+    }
+
+    /// Drop partially received requests, unsent responses, pending waits and
+    /// events, forwarded interrupts are masked
+    pub fn reset(&mut self) {
+        for irq in 0..128 {
+            if self.interrupts.is_enabled(irq) {
+                self.set_interrupt(irq, false).ok();
+            }
+        }
+        self.reset_pipe();
+        self.events.reset();
+    }
+
+    /// Drop partially received requests, unsent responses and pending waits
+    pub fn reset_pipe(&mut self) {
+        self.rx.reset();
+        self.tx.clear();
+        self.wait = None;
+        self.holding = false;
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            receiving: self.rx.is_active(),
+            responding: !self.tx.is_empty() || self.holding,
+            waiting: self.wait.is_some(),
+            events: self.events.len().min(u8::MAX as usize) as u8,
+        }
+    }
+
+    /// Answer vendor control IN request, returns the size of the reply
+    /// written into `buf` or `None` if the request is not supported
+    pub fn control_in(&self, request: u8, buf: &mut [u8]) -> Option<usize> {
+        match request {
+            REQUEST_STATUS => to_slice(&self.status(), buf).ok().map(|reply| reply.len()),
+            REQUEST_VERSION => {
+                buf.get_mut(..2)?
+                    .copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+
+    /// Execute vendor control OUT request, returns `false` if it is not supported
+    pub fn control_out(&mut self, request: u8) -> bool {
+        match request {
+            REQUEST_RESET_PIPE => {
+                self.reset_pipe();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Add received packet, a response is loaded once the request frame is complete
+    pub fn receive(&mut self, packet: &[u8]) -> Received {
+        let response = match self.rx.push(packet) {
+            Ok(Some(frame)) => match from_bytes::<Envelope>(frame) {
+                Ok(Envelope {
+                    tag,
+                    message:
+                        Message::WaitFor {
+                            address,
+                            mask,
+                            expected,
+                            timeout_us,
+                        },
+                }) => match self.start_wait(tag, address, mask, expected, timeout_us) {
+                    Some(message) => Envelope::new(tag, message),
+                    None => return Received::Waiting,
+                },
+                Ok(request) => Envelope::new(request.tag, self.handle(request.message)),
+                // The tag leads the envelope and may still be readable
+                Err(_) => Envelope::new(
+                    take_from_bytes(frame).map_or(Envelope::UNTAGGED, |(tag, _)| tag),
+                    Message::Error(ErrorCode::Decode),
+                ),
+            },
+            // Wait for the rest of the frame
+            Ok(None) => return Received::Incomplete,
+            Err(_) => Envelope::new(Envelope::UNTAGGED, Message::Error(ErrorCode::Decode)),
+        };
+
+        self.load(&response);
+        Received::Response
+    }
+
+    /// Write next packet of the loaded response into `buf`, returns its size
+    pub fn next_packet(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.tx.next_packet(buf)
+    }
+
+    /// Drop the rest of the loaded response
+    pub fn clear_response(&mut self) {
+        self.tx.clear();
+    }
+
+    pub fn wait_pending(&self) -> bool {
+        self.wait.is_some()
+    }
+
+    /// Check condition of the pending `WaitFor` request, `elapsed_us` passed
+    /// since the previous check. Returns `true` when the request completed
+    /// and its response is loaded.
+    pub fn poll_wait(&mut self, elapsed_us: u32) -> bool {
+        let wait = match self.wait.as_mut() {
+            Some(wait) => wait,
+            None => return false,
+        };
+
+        let message = match self.handler.read(wait.address, DataSize::U32) {
+            Ok(Data::U32(value)) if value & wait.mask == wait.expected => {
+                Message::Data(Data::U32(value))
+            }
+            Ok(_) if wait.remaining_us > elapsed_us => {
+                wait.remaining_us -= elapsed_us;
+                return false;
+            }
+            Ok(_) => Message::Error(ErrorCode::Timeout),
+            Err(code) => Message::Error(code),
+        };
+
+        let tag = wait.tag;
+        self.wait = None;
+        self.load(&Envelope::new(tag, message));
+        true
+    }
+
+    fn load(&mut self, response: &Envelope) {
+        if self.tx.load(response).is_err() {
+            // Every error response fits into a frame
+            let error = Message::Error(ErrorCode::UnsupportedSize);
+            self.tx.load(&Envelope::new(response.tag, error)).ok();
+        }
+    }
+
+    fn handle(&mut self, message: Message) -> Message {
+        self.process(message).unwrap_or_else(Message::Error)
+    }
+
+    fn process(&mut self, message: Message) -> Result<Message, ErrorCode> {
+        match message {
+            Message::Ping => Ok(Message::Pong),
+            Message::Hello => Ok(Message::Capabilities(self.capabilities())),
+            Message::Set(address, data) => {
+                self.write(address, data)?;
+                Ok(Message::Ack)
+            }
+            Message::Get(address, data_size) => Ok(Message::Data(self.read(address, data_size)?)),
+            Message::ReadBlock { address, len } => {
+                if len as usize > BLOCK_MAX_SIZE {
+                    return Err(ErrorCode::UnsupportedSize);
+                }
+                self.check_block(address, len as usize, Access::READ)?;
+                let mut bytes = Block::new();
+                // Capacity is checked above
+                bytes.resize_default(len as usize).ok();
+                self.handler.read_bytes(address, &mut bytes)?;
+                Ok(Message::Block(bytes))
+            }
+            Message::WriteBlock { address, bytes } => {
+                self.check_block(address, bytes.len(), Access::WRITE)?;
+                self.handler.write_bytes(address, &bytes)?;
+                Ok(Message::Ack)
+            }
+            Message::Modify {
+                address,
+                mask,
+                value,
+                width,
+            } => {
+                self.modify(address, width, mask, value)?;
+                Ok(Message::Ack)
+            }
+            Message::SetBits {
+                address,
+                bits,
+                width,
+            } => {
+                self.modify(address, width, bits, bits)?;
+                Ok(Message::Ack)
+            }
+            Message::ClearBits {
+                address,
+                bits,
+                width,
+            } => {
+                self.modify(address, width, bits, 0)?;
+                Ok(Message::Ack)
+            }
+            Message::Batch(operations) => Ok(self.batch(&operations)),
+            Message::Custom { id, payload } => {
+                let (_, command) = self
+                    .commands
+                    .iter()
+                    .find(|(known, _)| *known == id)
+                    .ok_or(ErrorCode::Unsupported)?;
+                let mut reply = Payload::new();
+                command(&mut self.handler, &payload, &mut reply)?;
+                Ok(Message::Custom { id, payload: reply })
+            }
+            Message::EnableInterrupt(irq) => self.set_interrupt(irq, true).map(|_| Message::Ack),
+            Message::DisableInterrupt(irq) => self.set_interrupt(irq, false).map(|_| Message::Ack),
+            _ => Err(ErrorCode::Unsupported),
+        }
+    }
+
+    fn set_interrupt(&mut self, irq: u8, enabled: bool) -> Result<(), ErrorCode> {
+        let bit = Interrupts::bit(irq);
+        if self.interrupts.allowed & bit == 0 {
+            return Err(ErrorCode::Forbidden);
+        }
+        self.handler.set_interrupt(irq, enabled)?;
+        if enabled {
+            self.interrupts.enabled |= bit;
+        } else {
+            self.interrupts.enabled &= !bit;
+        }
+        Ok(())
+    }
+
+    /// Check the spans of a block against the policy with the widths they
+    /// are accessed with, blocks must not wrap around the address space
+    fn check_block(&self, address: u32, len: usize, access: Access) -> Result<(), ErrorCode> {
+        if len > 0 && address.checked_add(len as u32 - 1).is_none() {
+            return Err(ErrorCode::Forbidden);
+        }
+        for (start, len, size) in block_spans(address, len) {
+            if len > 0 {
+                self.policy
+                    .check(start, len as u32, access | Access::width(size))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: Data) -> Result<(), ErrorCode> {
+        check_alignment(address, data.size())?;
+        self.policy.check_write(address, data.size())?;
+        self.handler.write(address, data)
+    }
+
+    fn read(&mut self, address: u32, size: DataSize) -> Result<Data, ErrorCode> {
+        check_alignment(address, size)?;
+        self.policy.check_read(address, size)?;
+        self.handler.read(address, size)
+    }
+
+    fn modify(
+        &mut self,
+        address: u32,
+        size: DataSize,
+        mask: u32,
+        value: u32,
+    ) -> Result<(), ErrorCode> {
+        check_alignment(address, size)?;
+        self.policy.check_read(address, size)?;
+        self.policy.check_write(address, size)?;
+        self.handler.modify(address, size, mask, value)
+    }
+
+    /// Execute operations in order, stopping at the first failed one
+    fn batch(&mut self, operations: &[Operation]) -> Message {
+        let mut results = BatchData::new();
+        let mut delayed_us = 0;
+
+        for (index, operation) in operations.iter().enumerate() {
+            let result = match *operation {
+                Operation::Set(address, data) => self.write(address, data),
+                // There are never more reads than operations
+                Operation::Get(address, size) => self
+                    .read(address, size)
+                    .map(|data| results.push(data).unwrap_or_default()),
+                Operation::Modify {
+                    address,
+                    mask,
+                    value,
+                    width,
+                } => self.modify(address, width, mask, value),
+                // The interrupt running the batch must not be blocked for long
+                Operation::Delay(us) if us <= BATCH_MAX_DELAY_US - delayed_us => {
+                    delayed_us += us;
+                    self.handler.delay_us(us);
+                    Ok(())
+                }
+                Operation::Delay(_) => Err(ErrorCode::UnsupportedSize),
+            };
+
+            if let Err(error) = result {
+                return Message::BatchError {
+                    index: index as u8,
+                    error,
+                };
+            }
+        }
+
+        Message::BatchResult(results)
+    }
+
+    /// Start `WaitFor` request, returns response if it fails right away
+    fn start_wait(
+        &mut self,
+        tag: u16,
+        address: u32,
+        mask: u32,
+        expected: u32,
+        timeout_us: u32,
+    ) -> Option<Message> {
+        if let Err(code) = check_alignment(address, DataSize::U32)
+            .and_then(|_| self.policy.check_read(address, DataSize::U32))
+        {
+            return Some(Message::Error(code));
+        }
+
+        self.wait = Some(PendingWait {
+            tag,
+            address,
+            mask,
+            expected,
+            remaining_us: timeout_us,
+        });
+        None
+    }
+}
+
+fn check_alignment(address: u32, size: DataSize) -> Result<(), ErrorCode> {
+    if address.is_multiple_of(size.bytes()) {
+        Ok(())
+    } else {
+        Err(ErrorCode::Unaligned)
+    }
+}
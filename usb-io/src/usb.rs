@@ -1,8 +1,24 @@
 pub const VID: u16 = 0x16c0;
 pub const PID: u16 = 0x27dd;
-pub const MANUFACTURER: &'static str = "USB-IO Manafacturer";
-pub const PRODUCT: &'static str = "USB-IO USB class";
-pub const SERIAL_NUMBER: &'static str = "USB-IO Serial Number";
-pub const MESSAGE_MAX_SIZE: u16 = 16;
+pub const MANUFACTURER: &str = "USB-IO Manafacturer";
+pub const PRODUCT: &str = "USB-IO USB class";
+pub const SERIAL_NUMBER: &str = "USB-IO Serial Number";
+pub const INTERFACE_GUID: &str = "{6a1f3b52-8e0d-4c7a-9b3e-5d2c7f40a1e9}";
+pub const PACKET_MAX_SIZE: usize = 64;
+pub const DEFAULT_PACKET_SIZE: usize = 64;
 pub const USB_IO_OUT_ENDPOINT: u8 = 0x1;
 pub const USB_IO_IN_ENDPOINT: u8 = 0x81;
+pub const USB_IO_EVENT_ENDPOINT: u8 = 0x82;
+/// Vendor control requests addressed to the USB-IO interface
+pub const REQUEST_STATUS: u8 = 0x01;
+pub const REQUEST_VERSION: u8 = 0x02;
+pub const REQUEST_RESET_PIPE: u8 = 0x03;
+pub const REQUEST_REBOOT: u8 = 0x04;
+pub const BLOCK_MAX_SIZE: usize = 128;
+pub const BATCH_MAX_OPS: usize = 16;
+/// Total busy wait of a batch, batches run in the USB interrupt
+pub const BATCH_MAX_DELAY_US: u32 = 1_000;
+pub const PAYLOAD_MAX_SIZE: usize = 128;
+pub const COMMANDS_MAX: usize = 8;
+pub const EVENTS_MAX: usize = 16;
+pub const FRAME_MAX_SIZE: usize = 320;